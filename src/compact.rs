@@ -0,0 +1,183 @@
+use {BPlusError, BPlusTree};
+use keyenc::{Decode, DecodeError, Encode};
+
+/************************* COMPACT WIRE FORMAT *************************/
+
+/*
+ * A bare bones format for shipping a whole tree: the entry count, then for
+ * each entry its key and value. Keys are stored as the gap from the key
+ * before (the first one as-is), written as a LEB128 varint, so a dense run
+ * of integer keys costs one byte per key. Values use their keyenc encoding.
+ * Nothing else is recorded, so both ends have to agree on K and V.
+ */
+
+/*
+ * Integer keys mapped onto u128 without changing their order. Signed
+ * types flip the sign bit so negative keys sit below positive ones.
+ */
+pub trait CompactKey: Sized {
+    fn to_bits(&self) -> u128;
+
+    /* None when the bits are outside the range of the type */
+    fn from_bits(bits: u128) -> Option<Self>;
+}
+
+macro_rules! impl_compact_unsigned {
+    ($($t:ty),*) => {
+        $(
+            impl CompactKey for $t {
+                fn to_bits(&self) -> u128 {
+                    *self as u128
+                }
+
+                fn from_bits(bits: u128) -> Option<Self> {
+                    if bits > <$t>::MAX as u128 { None } else { Some(bits as $t) }
+                }
+            }
+        )*
+    }
+}
+
+macro_rules! impl_compact_signed {
+    ($($t:ty => $u:ty),*) => {
+        $(
+            impl CompactKey for $t {
+                fn to_bits(&self) -> u128 {
+                    ((*self as $u) ^ (1 << (<$u>::BITS - 1))) as u128
+                }
+
+                fn from_bits(bits: u128) -> Option<Self> {
+                    <$u>::from_bits(bits).map(|u| (u ^ (1 << (<$u>::BITS - 1))) as $t)
+                }
+            }
+        )*
+    }
+}
+
+impl_compact_unsigned!(u8, u16, u32, u64, u128, usize);
+impl_compact_signed!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128, isize => usize);
+
+fn write_varint(mut n: u128, out: &mut Vec<u8>) {
+    while n >= 0x80 {
+        out.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+
+    out.push(n as u8);
+}
+
+fn read_varint(input: &mut &[u8]) -> Result<u128, DecodeError> {
+    let mut n: u128 = 0;
+    let mut shift = 0;
+
+    loop {
+        let (&b, rest) = input.split_first().ok_or(DecodeError::UnexpectedEnd)?;
+        *input = rest;
+
+        if shift >= 128 || (shift > 121 && (b & 0x7F) >> (128 - shift) != 0) {
+            return Err(DecodeError::Overflow);
+        }
+
+        n |= ((b & 0x7F) as u128) << shift;
+        shift += 7;
+
+        if b & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+}
+
+impl<K, V> BPlusTree<K, V>
+    where K: Ord + Copy + CompactKey, V: Copy + Encode + Decode
+{
+    pub fn to_compact_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut prev = 0;
+
+        write_varint(self.len() as u128, &mut out);
+
+        for (k, v) in self.entries() {
+            let bits = k.to_bits();
+
+            write_varint(bits - prev, &mut out);
+            v.encode(&mut out);
+            prev = bits;
+        }
+
+        out
+    }
+
+    /*
+     * Bulk loads a tree written by to_compact_bytes. A zero gap after the
+     * first key means the same key twice, which is reported as a
+     * DuplicateKey rather than silently keeping one of them.
+     */
+    pub fn from_compact_bytes(mut bytes: &[u8]) -> Result<Self, BPlusError<K>> {
+        let count = read_varint(&mut bytes)?;
+
+        /* don't trust the count for the allocation, every entry is at least two bytes */
+        let mut pairs: Vec<(K, V)> = Vec::with_capacity((count as usize).min(bytes.len() / 2));
+        let mut prev = 0_u128;
+
+        for i in 0..count {
+            let gap = read_varint(&mut bytes)?;
+
+            if i > 0 && gap == 0 {
+                return Err(BPlusError::DuplicateKey(pairs[pairs.len() - 1].0));
+            }
+
+            prev = prev.checked_add(gap).ok_or(DecodeError::Overflow)?;
+
+            let key = K::from_bits(prev).ok_or(DecodeError::Overflow)?;
+            pairs.push((key, V::decode(&mut bytes)?));
+        }
+
+        if !bytes.is_empty() {
+            return Err(DecodeError::TrailingBytes.into());
+        }
+
+        Ok(Self::from_sorted_vec(pairs))
+    }
+}
+
+/************************* TESTING PROGRAM *************************/
+#[cfg(test)]
+mod tests {
+    use {BPlusError, BPlusTree};
+    use keyenc::DecodeError;
+
+    #[test]
+    fn test_compact_round_trip() {
+        let dense = BPlusTree::<u64, u32>::from_iter_last_wins((1000..2000).map(|k| (k, k as u32)));
+        let bytes = dense.to_compact_bytes();
+
+        /* count, a two byte first key, then one byte per gap, plus the values */
+        assert_eq!(bytes.len(), 2 + 2 + 999 + 1000 * 4);
+
+        let copy = BPlusTree::<u64, u32>::from_compact_bytes(&bytes).unwrap();
+        assert_eq!(copy.entries().collect::<Vec<_>>(), dense.entries().collect::<Vec<_>>());
+        assert!(copy.validate());
+
+        let signed = BPlusTree::<i32, i8>::from_iter_last_wins(vec![(i32::MIN, -1), (-5, 0), (7, 1), (i32::MAX, 2)].into_iter());
+        let copy = BPlusTree::<i32, i8>::from_compact_bytes(&signed.to_compact_bytes()).unwrap();
+        assert_eq!(copy.entries().collect::<Vec<_>>(), signed.entries().collect::<Vec<_>>());
+
+        let empty = BPlusTree::<u8, u8>::new();
+        assert_eq!(empty.to_compact_bytes(), vec![0]);
+        assert!(BPlusTree::<u8, u8>::from_compact_bytes(&[0]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_compact_errors() {
+        /* two entries, the second with a zero gap */
+        assert_eq!(BPlusTree::<u8, u8>::from_compact_bytes(&[2, 5, 50, 0, 60]).err(), Some(BPlusError::DuplicateKey(5)));
+        assert_eq!(BPlusTree::<u8, u8>::from_compact_bytes(&[2, 5, 50]).err(), Some(DecodeError::UnexpectedEnd.into()));
+        assert_eq!(BPlusTree::<u8, u8>::from_compact_bytes(&[1, 5, 50, 9]).err(), Some(DecodeError::TrailingBytes.into()));
+
+        /* a key of 256 doesn't fit in a u8 */
+        assert_eq!(BPlusTree::<u8, u8>::from_compact_bytes(&[1, 0x80, 0x02, 50]).err(), Some(DecodeError::Overflow.into()));
+
+        /* a count that claims far more entries than there are bytes */
+        assert_eq!(BPlusTree::<u8, u8>::from_compact_bytes(&[0xFF, 0xFF, 0xFF, 0xFF, 0x0F]).err(), Some(DecodeError::UnexpectedEnd.into()));
+    }
+}
@@ -0,0 +1,168 @@
+use std::cmp::Reverse;
+use std::ops::RangeBounds;
+use BPlusTree;
+
+/************************* COUNTING MULTISET *************************/
+
+/*
+ * A tally of how many times each key has been seen, kept in a tree from
+ * key to count. A key whose count drops to zero is removed, so the tree
+ * only ever holds keys with a count of at least one.
+ *
+ * Counts saturate at u64::MAX rather than wrapping. The total is a u128
+ * and is always the exact sum of the stored counts, so a saturated add
+ * only adds what actually made it into the count.
+ */
+pub struct BPlusCounter<K: Ord + Copy> {
+    tree: BPlusTree<K, u64>,
+    total: u128
+}
+
+impl<K: Ord + Copy> Default for BPlusCounter<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord + Copy> BPlusCounter<K> {
+    pub fn new() -> Self {
+        BPlusCounter { tree: BPlusTree::new(), total: 0 }
+    }
+
+    /* Adds n to the key's count, starting it at n if it isn't there yet */
+    pub fn add(&mut self, key: K, n: u64) {
+        if n == 0 {
+            return;
+        }
+
+        let old = self.count(&key);
+        let new = old.saturating_add(n);
+
+        self.tree.insert(&key, &new);
+        self.total += (new - old) as u128;
+    }
+
+    /*
+     * Takes n off the key's count and returns how much was actually taken
+     * off. Taking off at least the whole count removes the key.
+     */
+    pub fn sub(&mut self, key: &K, n: u64) -> u64 {
+        let old = self.count(key);
+        let taken = old.min(n);
+
+        if taken == 0 {
+            return 0;
+        }
+
+        if taken == old {
+            self.tree.remove_entry(key);
+        } else {
+            self.tree.insert(key, &(old - taken));
+        }
+
+        self.total -= taken as u128;
+        taken
+    }
+
+    /* The key's count, which is 0 for keys that aren't there */
+    pub fn count(&self, key: &K) -> u64 {
+        self.tree.get(key).map_or(0, |c| *c)
+    }
+
+    /* The sum of every count */
+    pub fn total(&self) -> u128 {
+        self.total
+    }
+
+    /* How many distinct keys have a count */
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /* The keys and their counts within the range, in key order */
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> impl Iterator<Item = (&K, u64)> {
+        self.tree.range_entries(BPlusTree::<K, u64>::owned_bounds(&range)).map(|(k, c)| (k, *c))
+    }
+
+    /*
+     * The n keys with the highest counts, highest first. Keys with the
+     * same count come out in key order.
+     */
+    pub fn top_n(&self, n: usize) -> Vec<(K, u64)> {
+        let mut counts: Vec<(K, u64)> = self.tree.entries().map(|(k, c)| (*k, *c)).collect();
+
+        counts.sort_by_key(|&(_, c)| Reverse(c));
+        counts.truncate(n);
+        counts
+    }
+}
+
+/************************* TESTING PROGRAM *************************/
+#[cfg(test)]
+mod tests {
+    use counter::BPlusCounter;
+
+    #[test]
+    fn test_add_sub() {
+        let mut counter = BPlusCounter::new();
+
+        counter.add(5, 3);
+        counter.add(2, 1);
+        counter.add(5, 2);
+        assert_eq!(counter.count(&5), 5);
+        assert_eq!(counter.count(&9), 0);
+        assert_eq!(counter.total(), 6);
+
+        /* bounce a key through zero over and over */
+        for round in 1..20 {
+            counter.add(7, round);
+            assert_eq!(counter.sub(&7, round - 1), round - 1);
+            assert_eq!(counter.count(&7), 1);
+            assert_eq!(counter.sub(&7, 5), 1);
+            assert_eq!(counter.count(&7), 0);
+            assert_eq!(counter.len(), 2);
+            assert_eq!(counter.total(), 6);
+        }
+
+        assert_eq!(counter.sub(&9, 1), 0);
+        assert_eq!(counter.sub(&5, 0), 0);
+        assert_eq!(counter.sub(&2, 1), 1);
+        assert_eq!(counter.range(..).collect::<Vec<_>>(), vec![(&5, 5)]);
+        assert_eq!(counter.total(), 5);
+
+        counter.sub(&5, u64::MAX);
+        assert!(counter.is_empty());
+        assert_eq!(counter.total(), 0);
+    }
+
+    #[test]
+    fn test_saturation() {
+        let mut counter = BPlusCounter::new();
+
+        counter.add(1, u64::MAX - 1);
+        counter.add(1, 10);
+        counter.add(2, u64::MAX);
+        assert_eq!(counter.count(&1), u64::MAX);
+        assert_eq!(counter.total(), 2 * u64::MAX as u128);
+
+        assert_eq!(counter.sub(&1, 1), 1);
+        assert_eq!(counter.total(), 2 * u64::MAX as u128 - 1);
+    }
+
+    #[test]
+    fn test_top_n_and_range() {
+        let mut counter = BPlusCounter::new();
+
+        for &(k, n) in &[(1, 4), (2, 9), (3, 4), (4, 1)] {
+            counter.add(k, n);
+        }
+
+        assert_eq!(counter.top_n(3), vec![(2, 9), (1, 4), (3, 4)]);
+        assert_eq!(counter.top_n(10).len(), 4);
+        assert_eq!(counter.range(2..4).collect::<Vec<_>>(), vec![(&2, 9), (&3, 4)]);
+    }
+}
@@ -0,0 +1,342 @@
+use std::error::Error;
+use std::fmt;
+use std::mem;
+
+/************************* ORDER-PRESERVING KEY ENCODING *************************/
+
+/*
+ * These turn typed keys into byte strings whose plain memcmp order matches
+ * the order of the original values, so signed integers, floats, strings
+ * and tuples of them can all be stored as byte keys.
+ *
+ * - Unsigned integers are written big-endian.
+ * - Signed integers have their sign bit flipped first (offset binary), so
+ *   negative numbers sort before positive ones.
+ * - Floats follow total_cmp: positive values get their sign bit flipped and
+ *   negative values get every bit flipped. That means -0.0 sorts just before
+ *   0.0, and NaNs sort past the infinities on the side of their sign bit.
+ * - Strings and byte strings end with 0x00 0x01 and any 0x00 inside them is
+ *   written as 0x00 0xFF. A string therefore sorts before every longer
+ *   string it is a prefix of, even in the middle of a tuple.
+ * - Tuples are just their elements one after another. Every element knows
+ *   where it ends, so comparing the bytes compares element by element.
+ */
+pub trait Encode {
+    fn encode(&self, out: &mut Vec<u8>);
+
+    /* Convenience for encoding a whole key into a fresh buffer */
+    fn to_key(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode(&mut out);
+        out
+    }
+}
+
+/*
+ * The reverse of Encode. Decoding consumes bytes from the front of the
+ * input, so tuples can decode their elements one after another.
+ */
+pub trait Decode: Sized {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError>;
+
+    /* Decodes a whole key, failing if anything is left over */
+    fn from_key(mut input: &[u8]) -> Result<Self, DecodeError> {
+        let value = Self::decode(&mut input)?;
+
+        if !input.is_empty() {
+            return Err(DecodeError::TrailingBytes);
+        }
+
+        Ok(value)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    UnexpectedEnd,
+    BadEscape,
+    InvalidUtf8,
+    TrailingBytes,
+    Overflow
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::UnexpectedEnd => write!(f, "key ended in the middle of a value"),
+            DecodeError::BadEscape => write!(f, "invalid escape sequence in a string"),
+            DecodeError::InvalidUtf8 => write!(f, "string is not valid UTF-8"),
+            DecodeError::TrailingBytes => write!(f, "unexpected bytes after the end of the key"),
+            DecodeError::Overflow => write!(f, "value does not fit in the target type")
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+/* Splits n bytes off the front of the input */
+fn take<'a>(input: &mut &'a [u8], n: usize) -> Result<&'a [u8], DecodeError> {
+    if input.len() < n {
+        return Err(DecodeError::UnexpectedEnd);
+    }
+
+    let (head, tail) = input.split_at(n);
+    *input = tail;
+    Ok(head)
+}
+
+macro_rules! impl_unsigned {
+    ($($t:ty),*) => {
+        $(
+            impl Encode for $t {
+                fn encode(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_be_bytes());
+                }
+            }
+
+            impl Decode for $t {
+                fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+                    let mut bytes = [0; mem::size_of::<$t>()];
+                    bytes.copy_from_slice(take(input, mem::size_of::<$t>())?);
+                    Ok(<$t>::from_be_bytes(bytes))
+                }
+            }
+        )*
+    }
+}
+
+macro_rules! impl_signed {
+    ($($t:ty => $u:ty),*) => {
+        $(
+            impl Encode for $t {
+                fn encode(&self, out: &mut Vec<u8>) {
+                    ((*self as $u) ^ (1 << (<$u>::BITS - 1))).encode(out);
+                }
+            }
+
+            impl Decode for $t {
+                fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+                    Ok((<$u>::decode(input)? ^ (1 << (<$u>::BITS - 1))) as $t)
+                }
+            }
+        )*
+    }
+}
+
+macro_rules! impl_float {
+    ($($t:ty => $u:ty),*) => {
+        $(
+            impl Encode for $t {
+                fn encode(&self, out: &mut Vec<u8>) {
+                    let bits = self.to_bits();
+                    let sign = 1 << (<$u>::BITS - 1);
+
+                    if bits & sign == 0 { bits ^ sign } else { !bits }.encode(out);
+                }
+            }
+
+            impl Decode for $t {
+                fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+                    let bits = <$u>::decode(input)?;
+                    let sign = 1 << (<$u>::BITS - 1);
+
+                    Ok(<$t>::from_bits(if bits & sign == 0 { !bits } else { bits ^ sign }))
+                }
+            }
+        )*
+    }
+}
+
+impl_unsigned!(u8, u16, u32, u64, u128);
+impl_signed!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+impl_float!(f32 => u32, f64 => u64);
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    for &b in bytes {
+        out.push(b);
+
+        if b == 0x00 {
+            out.push(0xFF);
+        }
+    }
+
+    out.extend_from_slice(&[0x00, 0x01]);
+}
+
+fn decode_bytes(input: &mut &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let mut bytes = Vec::new();
+
+    loop {
+        match take(input, 1)?[0] {
+            0x00 => match take(input, 1)?[0] {
+                0x01 => return Ok(bytes),
+                0xFF => bytes.push(0x00),
+                _ => return Err(DecodeError::BadEscape)
+            },
+            b => bytes.push(b)
+        }
+    }
+}
+
+impl Encode for &str {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_bytes(self.as_bytes(), out);
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_bytes(self.as_bytes(), out);
+    }
+}
+
+impl Decode for String {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        String::from_utf8(decode_bytes(input)?).map_err(|_| DecodeError::InvalidUtf8)
+    }
+}
+
+impl Encode for &[u8] {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_bytes(self, out);
+    }
+}
+
+impl Encode for Vec<u8> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_bytes(self, out);
+    }
+}
+
+impl Decode for Vec<u8> {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        decode_bytes(input)
+    }
+}
+
+impl<A: Encode, B: Encode> Encode for (A, B) {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.0.encode(out);
+        self.1.encode(out);
+    }
+}
+
+impl<A: Decode, B: Decode> Decode for (A, B) {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok((A::decode(input)?, B::decode(input)?))
+    }
+}
+
+impl<A: Encode, B: Encode, C: Encode> Encode for (A, B, C) {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.0.encode(out);
+        self.1.encode(out);
+        self.2.encode(out);
+    }
+}
+
+impl<A: Decode, B: Decode, C: Decode> Decode for (A, B, C) {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok((A::decode(input)?, B::decode(input)?, C::decode(input)?))
+    }
+}
+
+/************************* TESTING PROGRAM *************************/
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+    use std::fmt::Debug;
+    use keyenc::{Decode, DecodeError, Encode};
+
+    /* A simple LCG so the generated keys are scattered but repeatable */
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self) -> u64 {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            self.0
+        }
+
+        fn string(&mut self) -> String {
+            /* a tiny alphabet, including the escape byte, so prefixes are common */
+            let len = (self.next() >> 60) as usize % 5;
+            (0..len).map(|_| ['\0', '\u{1}', 'a', 'b', '\u{ff}'][(self.next() >> 40) as usize % 5]).collect()
+        }
+    }
+
+    /* Checks that the encoded order matches cmp for every pair, and that everything decodes */
+    fn check_order<T, F>(values: &[T], cmp: F)
+        where T: Encode + Decode + Debug, F: Fn(&T, &T) -> Ordering
+    {
+        for a in values {
+            for b in values {
+                assert_eq!(a.to_key().cmp(&b.to_key()), cmp(a, b), "{:?} vs {:?}", a, b);
+            }
+
+            assert_eq!(T::from_key(&a.to_key()).unwrap().to_key(), a.to_key());
+        }
+    }
+
+    #[test]
+    fn test_integer_order() {
+        let mut rng = Lcg(1);
+
+        let mut signed: Vec<i64> = (0..200).map(|_| rng.next() as i64).collect();
+        signed.extend_from_slice(&[i64::MIN, -1, 0, 1, i64::MAX]);
+        check_order(&signed, |a, b| a.cmp(b));
+
+        let small: Vec<i8> = (i8::MIN..=i8::MAX).collect();
+        check_order(&small, |a, b| a.cmp(b));
+
+        let unsigned: Vec<u32> = (0..200).map(|_| rng.next() as u32).chain(vec![0, u32::MAX]).collect();
+        check_order(&unsigned, |a, b| a.cmp(b));
+    }
+
+    #[test]
+    fn test_float_order() {
+        let mut rng = Lcg(2);
+
+        let mut floats: Vec<f64> = (0..200).map(|_| f64::from_bits(rng.next())).collect();
+        floats.extend_from_slice(&[0.0, -0.0, 1.0, -1.0, f64::INFINITY, f64::NEG_INFINITY,
+                                   f64::NAN, -f64::NAN, f64::MIN_POSITIVE, f64::MAX, f64::MIN]);
+        check_order(&floats, |a, b| a.total_cmp(b));
+
+        assert!((-0.0_f64).to_key() < 0.0_f64.to_key());
+        assert!(f64::INFINITY.to_key() < f64::NAN.to_key());
+        assert!((-f64::NAN).to_key() < f64::NEG_INFINITY.to_key());
+        assert!(f32::from_key(&1.5_f32.to_key()) == Ok(1.5));
+    }
+
+    #[test]
+    fn test_string_order() {
+        let mut rng = Lcg(3);
+
+        let mut strings: Vec<String> = (0..150).map(|_| rng.string()).collect();
+        strings.extend(vec!["".to_string(), "\0".to_string(), "\0\0".to_string(), "a".to_string(), "a\0".to_string()]);
+        check_order(&strings, |a, b| a.cmp(b));
+
+        assert_eq!(Vec::<u8>::from_key(&vec![0, 255, 1].to_key()), Ok(vec![0, 255, 1]));
+    }
+
+    #[test]
+    fn test_tuple_order() {
+        let mut rng = Lcg(4);
+
+        let pairs: Vec<(String, u32)> = (0..120).map(|_| (rng.string(), (rng.next() >> 62) as u32)).collect();
+        check_order(&pairs, |a, b| a.cmp(b));
+
+        let triples: Vec<(i16, String, i8)> = (0..120)
+            .map(|_| ((rng.next() >> 62) as i16 - 2, rng.string(), rng.next() as i8))
+            .collect();
+        check_order(&triples, |a, b| a.cmp(b));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(u32::from_key(&[1, 2]), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(u8::from_key(&[1, 2]), Err(DecodeError::TrailingBytes));
+        assert_eq!(String::from_key(&[b'a', 0x00, 0x07]), Err(DecodeError::BadEscape));
+        assert_eq!(String::from_key(b"a"), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(String::from_key(&[0xC0, 0x00, 0x01]), Err(DecodeError::InvalidUtf8));
+    }
+}
@@ -1,9 +1,34 @@
 use std::rc::Rc;
 use std::rc::Weak;
-use std::borrow::BorrowMut;
+#[cfg(test)]
+use std::cell::Cell;
+use std::cmp;
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+use std::collections::BinaryHeap;
+use std::error::Error;
+use std::fmt;
+use std::iter;
+use std::mem;
+use std::ops::Bound;
+use std::ops::RangeBounds;
+use std::ptr;
+use std::vec;
+
+pub mod keyenc;
+pub mod compact;
+pub mod counter;
+
+use keyenc::{Decode, DecodeError, Encode};
 
 /************************* B+ TREE IMPLEMENTATION *************************/
 
+/*
+ * The most keys a node holds before it has to split. Interior nodes can
+ * have one more child than this.
+ */
+const MAX_KEYS: usize = 4;
+
 /*
  * I want the keys to implement Ord so that I can just use <,=,> to decide
  * where to place them. I also want the keys to implement Copy because I
@@ -14,7 +39,9 @@ use std::borrow::BorrowMut;
  * so this needs to be an Option. I'm using Weak references here so that I
  * can break the resulting reference cycles.
  */
+#[derive(Clone)]
 struct BPlusLeaf<K: Ord + Copy, V: Copy> {
+    #[allow(dead_code)]
     parent: Option<Weak<BPlusInterior<K, V>>>,
     keys: Vec<K>,
     values: Vec<V>,
@@ -27,37 +54,756 @@ struct BPlusLeaf<K: Ord + Copy, V: Copy> {
  * want these nodes allocated on the heap, but I am only using Rc
  * because I am using Rc::Weak for the parent pointer.
  */
+#[derive(Clone)]
 struct BPlusInterior<K: Ord + Copy, V: Copy> {
+    #[allow(dead_code)]
     parent: Option<Weak<BPlusInterior<K, V>>>,
     keys: Vec<K>,
     children: Vec<Rc<BPlusNode<K, V>>>
 }
 
+impl<K: Ord + Copy, V: Copy> BPlusInterior<K, V> {
+    /*
+     * Which child would hold the key. Child i holds the keys less than
+     * keys[i] and the last child holds everything greater than or equal
+     * to the last key.
+     */
+    fn child_index(&self, key: &K) -> usize {
+        match self.keys.binary_search(key) {
+            Ok(i) => i + 1,
+            Err(i) => i
+        }
+    }
+}
+
+/*
+ * A leaf along with the separators on either side of the path that led to
+ * it. Every key in [lower, upper) belongs in this leaf, and None means that
+ * side is unbounded.
+ */
+struct BoundedLeaf<'a, K: Ord + Copy + 'a, V: Copy + 'a> {
+    leaf: &'a BPlusLeaf<K, V>,
+    lower: Option<&'a K>,
+    upper: Option<&'a K>
+}
+
+/*
+ * A position in the tree for walking entries in order, forwards or
+ * backwards. The stack holds the interior nodes above the current leaf,
+ * each with the index of the next child to visit going forwards, or how
+ * many children are still to visit going backwards. Moving on to the next
+ * leaf only touches the nodes between the two leaves, so a walk never has
+ * to know about the whole tree up front.
+ */
+struct Cursor<'a, K: Ord + Copy + 'a, V: Copy + 'a> {
+    stack: Vec<(&'a BPlusInterior<K, V>, usize)>,
+    leaf: Option<&'a BPlusLeaf<K, V>>,
+    pos: usize,
+    forward: bool
+}
+
+impl<'a, K: Ord + Copy, V: Copy> Cursor<'a, K, V> {
+    /*
+     * Positions a forward cursor on the first entry that isn't before the
+     * start. before has to be true for some run of the smallest keys and
+     * false for everything after, so we can descend by it.
+     */
+    fn seek<F: Fn(&K) -> bool>(root: Option<&'a Rc<BPlusNode<K, V>>>, before: F) -> Self {
+        let mut cursor = Cursor { stack: Vec::new(), leaf: None, pos: 0, forward: true };
+        let mut node = match root {
+            Some(root) => root,
+            None => return cursor
+        };
+
+        loop {
+            match **node {
+                BPlusNode::Interior(ref interior) => {
+                    let i = interior.keys.partition_point(|k| before(k));
+                    cursor.stack.push((interior, i + 1));
+                    node = &interior.children[i];
+                },
+                BPlusNode::Leaf(ref leaf) => {
+                    cursor.pos = leaf.keys.partition_point(|k| before(k));
+                    cursor.leaf = Some(leaf);
+                    return cursor;
+                }
+            }
+        }
+    }
+
+    /*
+     * The mirror image of seek: a backward cursor on the last entry that
+     * isn't after the end. after has to be false for the smallest keys and
+     * true from some point on.
+     */
+    fn seek_back<F: Fn(&K) -> bool>(root: Option<&'a Rc<BPlusNode<K, V>>>, after: F) -> Self {
+        let mut cursor = Cursor { stack: Vec::new(), leaf: None, pos: 0, forward: false };
+        let mut node = match root {
+            Some(root) => root,
+            None => return cursor
+        };
+
+        loop {
+            match **node {
+                BPlusNode::Interior(ref interior) => {
+                    let i = interior.keys.partition_point(|k| !after(k));
+                    cursor.stack.push((interior, i));
+                    node = &interior.children[i];
+                },
+                BPlusNode::Leaf(ref leaf) => {
+                    cursor.pos = leaf.keys.partition_point(|k| !after(k));
+                    cursor.leaf = Some(leaf);
+                    return cursor;
+                }
+            }
+        }
+    }
+
+    /* Climbs to the nearest ancestor with a child left to visit and descends to the leaf at its edge */
+    fn next_leaf(&mut self) {
+        self.leaf = None;
+
+        while let Some((interior, next)) = self.stack.pop() {
+            let child = if self.forward {
+                if next == interior.children.len() {
+                    continue;
+                }
+
+                self.stack.push((interior, next + 1));
+                next
+            } else {
+                if next == 0 {
+                    continue;
+                }
+
+                self.stack.push((interior, next - 1));
+                next - 1
+            };
+
+            let mut node = &interior.children[child];
+            while let BPlusNode::Interior(ref interior) = **node {
+                let edge = if self.forward { 0 } else { interior.children.len() - 1 };
+
+                self.stack.push((interior, if self.forward { 1 } else { edge }));
+                node = &interior.children[edge];
+            }
+
+            if let BPlusNode::Leaf(ref leaf) = **node {
+                self.pos = if self.forward { 0 } else { leaf.keys.len() };
+                self.leaf = Some(leaf);
+            }
+
+            return;
+        }
+    }
+}
+
+impl<'a, K: Ord + Copy, V: Copy> Iterator for Cursor<'a, K, V> {
+    type Item = Entry<'a, K, V>;
+
+    fn next(&mut self) -> Option<Entry<'a, K, V>> {
+        loop {
+            let leaf = self.leaf?;
+
+            if self.forward && self.pos < leaf.keys.len() {
+                self.pos += 1;
+                return Some((&leaf.keys[self.pos - 1], &leaf.values[self.pos - 1]));
+            }
+
+            if !self.forward && self.pos > 0 {
+                self.pos -= 1;
+                return Some((&leaf.keys[self.pos], &leaf.values[self.pos]));
+            }
+
+            self.next_leaf();
+        }
+    }
+}
+
 /*
  * I am using this enum so that BPlusInterior.children can be either
  * interior nodes or leaves.
  */
+#[derive(Clone)]
 enum BPlusNode<K: Ord + Copy, V: Copy> {
     Leaf(BPlusLeaf<K, V>),
     Interior(BPlusInterior<K, V>)
 }
 
+/*
+ * Everything that can go wrong in the fallible tree operations. Variants
+ * that are about a particular key hand that key back to the caller.
+ */
+#[derive(Debug, PartialEq, Eq)]
+pub enum BPlusError<K> {
+    DuplicateKey(K),
+    Overlap(K),
+    Decode(DecodeError)
+}
+
+impl<K: fmt::Debug> fmt::Display for BPlusError<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BPlusError::DuplicateKey(ref k) => write!(f, "duplicate key: {:?}", k),
+            BPlusError::Overlap(ref k) => write!(f, "key {:?} is not above every key in the tree", k),
+            BPlusError::Decode(ref e) => write!(f, "could not decode tree: {}", e)
+        }
+    }
+}
+
+impl<K> From<DecodeError> for BPlusError<K> {
+    fn from(e: DecodeError) -> Self {
+        BPlusError::Decode(e)
+    }
+}
+
+impl<K: fmt::Debug> Error for BPlusError<K> {}
+
+/*
+ * A failed compare_and_swap. Holds the value that was actually stored (or
+ * None if the key was absent) and hands back the value that wasn't written.
+ */
+#[derive(Debug, PartialEq, Eq)]
+pub struct CasError<V> {
+    pub current: Option<V>,
+    pub rejected: Option<V>
+}
+
+impl<V: fmt::Debug> fmt::Display for CasError<V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "compare and swap failed, current value is {:?}", self.current)
+    }
+}
+
+impl<V: fmt::Debug> Error for CasError<V> {}
+
+/*
+ * One difference between two trees, going from an old tree to a new one.
+ * Removed and Changed carry the old value along so a diff can be read on
+ * its own; apply_diff only needs the new side.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Diff<K, V> {
+    Added(K, V),
+    Removed(K, V),
+    Changed(K, V, V)
+}
+
+/* The owned entries removed by drain_range, in key order */
+pub struct DrainRange<K, V> {
+    entries: vec::IntoIter<(K, V)>
+}
+
+impl<K, V> Iterator for DrainRange<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        self.entries.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl<K, V> DoubleEndedIterator for DrainRange<K, V> {
+    fn next_back(&mut self) -> Option<(K, V)> {
+        self.entries.next_back()
+    }
+}
+
+impl<K, V> ExactSizeIterator for DrainRange<K, V> {}
+
+/* A borrowed key / value pair, as handed out by the lookup methods */
+pub type Entry<'a, K, V> = (&'a K, &'a V);
+
+/* What a node hands up after splitting: the separator and its new right sibling */
+type Split<K, V> = Option<(K, Rc<BPlusNode<K, V>>)>;
+
 /*
  * This is meant to be the externally-facing struct that eternal code
  * would call methods on. I will probably want to add fields in the
  * future, but for the moment I am already sufficiently confused. :P
  */
-struct BPlusTree<K: Ord + Copy, V: Copy> {
-    root: Option<Rc<BPlusNode<K, V>>>
+pub struct BPlusTree<K: Ord + Copy, V: Copy> {
+    root: Option<Rc<BPlusNode<K, V>>>,
+
+    /*
+     * The smallest and largest keys in the tree, so lookups for keys that
+     * are out of bounds can bail before descending at all.
+     */
+    min_key: Option<K>,
+    max_key: Option<K>,
+
+    /* How many entries are stored, so len() doesn't have to count them */
+    size: usize,
+
+    /* Emptied leaves put aside by clear_retaining for reload to fill */
+    spare: Vec<BPlusLeaf<K, V>>,
+
+    /* The share of a full node's entries that stay in the left half when insert splits it */
+    split_ratio: f64,
+
+    /* How many nodes have split, and how many pairs have been folded into one, over the tree's life */
+    splits: u64,
+    merges: u64,
+
+    /* How many interior nodes lookups have passed through */
+    #[cfg(test)]
+    descents: Cell<usize>
+}
+
+impl<K: Ord + Copy, V: Copy> Default for BPlusTree<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<K: Ord + Copy, V: Copy> BPlusTree<K, V> {
     /* Simple constructor */
-    fn new() -> Self {
-        return BPlusTree { root: None }
+    pub fn new() -> Self {
+        BPlusTree {
+            root: None,
+            min_key: None,
+            max_key: None,
+            size: 0,
+            spare: Vec::new(),
+            split_ratio: 0.5,
+            splits: 0,
+            merges: 0,
+            #[cfg(test)]
+            descents: Cell::new(0)
+        }
+    }
+
+    /*
+     * Builds a tree by k-way merging several individually sorted sources.
+     * A heap holds the next key from each source, tagged with the index of
+     * the source it came from. When the same key shows up in more than one
+     * source the later source pops last and overwrites the earlier values,
+     * so the last source wins. The merged run comes out sorted, so it is
+     * bulk loaded rather than inserted an entry at a time.
+     */
+    pub fn from_sorted_sources(mut sources: Vec<Box<dyn Iterator<Item = (K, V)>>>) -> Self {
+        let mut merged: Vec<(K, V)> = Vec::new();
+        let mut heads = BinaryHeap::new();
+        let mut values = Vec::with_capacity(sources.len());
+
+        for (i, source) in sources.iter_mut().enumerate() {
+            match source.next() {
+                Some((k, v)) => {
+                    heads.push(Reverse((k, i)));
+                    values.push(Some(v));
+                },
+                None => values.push(None)
+            }
+        }
+
+        while let Some(Reverse((k, i))) = heads.pop() {
+            let v = values[i].take().unwrap();
+
+            match merged.last_mut() {
+                Some(last) if last.0 == k => last.1 = v,
+                _ => merged.push((k, v))
+            }
+
+            if let Some((k, v)) = sources[i].next() {
+                heads.push(Reverse((k, i)));
+                values[i] = Some(v);
+            }
+        }
+
+        Self::from_sorted_vec(merged)
+    }
+
+    /*
+     * Merges whole trees into one, with the same heap of (key, input)
+     * heads as from_sorted_sources. Ties on a key pop in input order, so
+     * when a key is in more than one tree the resolver gets its values in
+     * the order the trees were passed in. The merged entries are bulk
+     * loaded, which leaves the result packed.
+     */
+    pub fn merge_many<F>(trees: Vec<BPlusTree<K, V>>, mut resolve: F) -> Self
+        where F: FnMut(&K, Vec<V>) -> V
+    {
+        let mut sources: Vec<_> = trees.iter().map(|tree| tree.entries()).collect();
+        let mut heads = BinaryHeap::new();
+        let mut values = Vec::with_capacity(sources.len());
+        let mut merged = Vec::new();
+
+        for (i, source) in sources.iter_mut().enumerate() {
+            match source.next() {
+                Some((k, v)) => {
+                    heads.push(Reverse((*k, i)));
+                    values.push(Some(*v));
+                },
+                None => values.push(None)
+            }
+        }
+
+        while let Some(Reverse((k, i))) = heads.pop() {
+            let mut inputs = vec![i];
+
+            while heads.peek().is_some_and(|&Reverse((next, _))| next == k) {
+                let Reverse((_, j)) = heads.pop().unwrap();
+                inputs.push(j);
+            }
+
+            let mut found: Vec<V> = inputs.iter().map(|&j| values[j].take().unwrap()).collect();
+            let v = if found.len() == 1 { found.pop().unwrap() } else { resolve(&k, found) };
+            merged.push((k, v));
+
+            for j in inputs {
+                if let Some((k, v)) = sources[j].next() {
+                    heads.push(Reverse((*k, j)));
+                    values[j] = Some(*v);
+                }
+            }
+        }
+
+        Self::from_sorted_vec(merged)
+    }
+
+    /*
+     * Builds a tree from pairs that may repeat keys, keeping the value that
+     * came last for each key. See from_unsorted_vec.
+     */
+    pub fn from_iter_last_wins<I: Iterator<Item = (K, V)>>(iter: I) -> Self {
+        Self::from_unsorted_vec(iter.collect())
+    }
+
+    /*
+     * The insert-if-absent counterpart of from_iter_last_wins: for a key
+     * that repeats, the first value seen is kept. The stable sort leaves
+     * it at the front of its group and dedup keeps the front.
+     */
+    pub fn from_iter_first_wins<I: Iterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut pairs: Vec<(K, V)> = iter.collect();
+
+        pairs.sort_by_key(|&(k, _)| k);
+        pairs.dedup_by_key(|&mut (k, _)| k);
+        Self::from_sorted_vec(pairs)
+    }
+
+    /*
+     * Sorts the pairs, drops repeated keys and bulk loads what is left.
+     * The sort is stable, so among pairs with the same key the last one
+     * is still last, and dedup_by copies it over the one that is kept.
+     * That gives the same result as inserting the pairs one at a time.
+     * The sort and dedup both happen in place in the caller's Vec.
+     */
+    pub fn from_unsorted_vec(mut pairs: Vec<(K, V)>) -> Self {
+        Self::sort_last_wins(&mut pairs);
+        Self::from_sorted_vec(pairs)
+    }
+
+    fn sort_last_wins(pairs: &mut Vec<(K, V)>) {
+        pairs.sort_by_key(|&(k, _)| k);
+        pairs.dedup_by(|later, kept| {
+            if later.0 == kept.0 {
+                kept.1 = later.1;
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    pub fn from_unsorted_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        Self::from_unsorted_vec(iter.into_iter().collect())
+    }
+
+    /*
+     * Builds a tree bottom up from pairs that are already sorted and free
+     * of duplicate keys. Every node is packed full except possibly the
+     * last two on each level, which split whatever is left between them.
+     */
+    fn from_sorted_vec(pairs: Vec<(K, V)>) -> Self {
+        let mut tree = Self::new();
+
+        tree.root = Self::build_from_sorted(&pairs, Vec::new());
+        tree.size = pairs.len();
+        tree.refresh_key_bounds();
+        tree
+    }
+
+    /*
+     * Does the work for from_sorted_vec. Leaves are taken from spare when
+     * there are any left, so a rebuild can reuse the Vec allocations of
+     * the leaves it is replacing instead of going back to the allocator.
+     */
+    fn build_from_sorted(pairs: &[(K, V)], mut spare: Vec<BPlusLeaf<K, V>>) -> Option<Rc<BPlusNode<K, V>>> {
+        let mut rest = pairs;
+        let chunks = Self::chunk_sizes(pairs.len(), MAX_KEYS, MAX_KEYS / 2).into_iter().map(|size| {
+            let (chunk, tail) = rest.split_at(size);
+            rest = tail;
+            chunk
+        });
+
+        let mut level: Vec<(K, Rc<BPlusNode<K, V>>)> = chunks.map(|chunk| {
+            let mut leaf = spare.pop().unwrap_or_else(|| BPlusLeaf {
+                parent: None,
+                keys: Vec::with_capacity(MAX_KEYS),
+                values: Vec::with_capacity(MAX_KEYS),
+            });
+
+            leaf.keys.clear();
+            leaf.values.clear();
+            leaf.keys.extend(chunk.iter().map(|&(k, _)| k));
+            leaf.values.extend(chunk.iter().map(|&(_, v)| v));
+
+            (chunk[0].0, Rc::new(BPlusNode::Leaf(leaf)))
+        }).collect();
+
+        /*
+         * Each node is tagged with the smallest key under it, which becomes
+         * its separator in the level above.
+         */
+        while level.len() > 1 {
+            let level_len = level.len();
+            let mut next = Vec::new();
+            let mut nodes = level.into_iter();
+
+            for size in Self::chunk_sizes(level_len, MAX_KEYS + 1, (MAX_KEYS + 2) / 2) {
+                let chunk: Vec<_> = nodes.by_ref().take(size).collect();
+                let first = chunk[0].0;
+
+                next.push((first, Rc::new(BPlusNode::Interior(BPlusInterior {
+                    parent: None,
+                    keys: chunk[1..].iter().map(|&(k, _)| k).collect(),
+                    children: chunk.into_iter().map(|(_, node)| node).collect()
+                }))));
+            }
+
+            level = next;
+        }
+
+        level.pop().map(|(_, node)| node)
+    }
+
+    /*
+     * How to cut n items into nodes of at most max each. Every node is
+     * full except the last, and if that one would come out under min the
+     * last two share what is left between them instead, so only the root
+     * can end up short.
+     */
+    fn chunk_sizes(n: usize, max: usize, min: usize) -> Vec<usize> {
+        let mut sizes = vec![max; n / max];
+
+        if !n.is_multiple_of(max) {
+            sizes.push(n % max);
+        }
+
+        if sizes.len() > 1 && sizes[sizes.len() - 1] < min {
+            let last_two = sizes.pop().unwrap() + sizes.pop().unwrap();
+
+            sizes.push(last_two / 2);
+            sizes.push(last_two - last_two / 2);
+        }
+
+        sizes
+    }
+
+    /*
+     * Repacks the tree in place so the leaves are full. The old leaves are
+     * pulled out of the tree and handed to the rebuild, which refills
+     * their Vecs rather than allocating new ones.
+     */
+    pub fn compact(&mut self) {
+        let pairs: Vec<(K, V)> = self.entries().map(|(k, v)| (*k, *v)).collect();
+        let mut spare = Vec::new();
+
+        if let Some(root) = self.root.take() {
+            Self::take_leaves(root, &mut spare);
+        }
+
+        self.root = Self::build_from_sorted(&pairs, spare);
+    }
+
+    /*
+     * Empties the tree but holds on to its leaves, Vec capacity and all,
+     * so the next reload can refill them instead of allocating.
+     */
+    pub fn clear_retaining(&mut self) {
+        if let Some(root) = self.root.take() {
+            Self::take_leaves(root, &mut self.spare);
+        }
+
+        for leaf in &mut self.spare {
+            leaf.keys.clear();
+            leaf.values.clear();
+        }
+
+        self.size = 0;
+        self.min_key = None;
+        self.max_key = None;
+    }
+
+    /*
+     * Replaces everything in the tree with the pairs, which are handled
+     * like from_unsorted_vec: the last value for a repeated key wins. The
+     * rebuild takes its leaves from the ones clear_retaining put aside and
+     * from the tree's current leaves before it allocates any new ones.
+     */
+    pub fn reload(&mut self, mut pairs: Vec<(K, V)>) {
+        Self::sort_last_wins(&mut pairs);
+
+        let mut spare = mem::take(&mut self.spare);
+        if let Some(root) = self.root.take() {
+            Self::take_leaves(root, &mut spare);
+        }
+
+        self.root = Self::build_from_sorted(&pairs, spare);
+        self.size = pairs.len();
+        self.refresh_key_bounds();
+    }
+
+    /* Tears a subtree apart, keeping only its leaves */
+    fn take_leaves(node: Rc<BPlusNode<K, V>>, leaves: &mut Vec<BPlusLeaf<K, V>>) {
+        match Rc::try_unwrap(node).ok().expect("Someone else is borrowing this node") {
+            BPlusNode::Leaf(leaf) => leaves.push(leaf),
+            BPlusNode::Interior(interior) => {
+                for child in interior.children {
+                    Self::take_leaves(child, leaves);
+                }
+            }
+        }
+    }
+
+    /* Consumes the tree, handing back its keys in order */
+    pub fn into_keys(self) -> impl Iterator<Item = K> {
+        self.into_leaves().into_iter().flat_map(|leaf| leaf.keys)
+    }
+
+    /* Consumes the tree, handing back its values in key order */
+    pub fn into_values(self) -> impl Iterator<Item = V> {
+        self.into_leaves().into_iter().flat_map(|leaf| leaf.values)
+    }
+
+    fn into_leaves(mut self) -> Vec<BPlusLeaf<K, V>> {
+        let mut leaves = Vec::new();
+
+        if let Some(root) = self.root.take() {
+            Self::take_leaves(root, &mut leaves);
+        }
+
+        leaves
+    }
+
+    /*
+     * Builds a fully packed copy of the tree, leaving this one alone. Handy
+     * for comparing against a layout that has been fragmented by updates.
+     */
+    pub fn compact_into(&self) -> BPlusTree<K, V> {
+        let mut tree = Self::from_sorted_vec(self.entries().map(|(k, v)| (*k, *v)).collect());
+
+        tree.split_ratio = self.split_ratio;
+        tree
+    }
+
+    /*
+     * The key bytes referenced from every node, counting each separator
+     * copy in the interior nodes as well as the keys in the leaves.
+     */
+    pub fn total_key_bytes(&self) -> usize
+        where K: KeySize
+    {
+        let mut total = 0;
+        self.visit_preorder(|node| total += node.keys().iter().map(KeySize::key_size).sum::<usize>());
+        total
+    }
+
+    /*
+     * How full the leaves are on average, as the fraction of MAX_KEYS
+     * slots that hold an entry.
+     */
+    pub fn utilization(&self) -> f64 {
+        let leaves = self.leaves();
+
+        if leaves.is_empty() {
+            return 0.0;
+        }
+
+        let entries: usize = leaves.iter().map(|leaf| leaf.keys.len()).sum();
+        entries as f64 / (leaves.len() * MAX_KEYS) as f64
+    }
+
+    /*
+     * A rough median found in one descent, without counting anything. We
+     * aim for the point halfway through the tree: in a node with c
+     * children that point falls in child floor(c / 2), and we carry on
+     * down aiming for wherever it lands inside that child. So with an odd
+     * number of children it's the middle one, and with an even number
+     * it's the start of the right half. This is exact when the nodes are
+     * equally full, and lopsided trees pull it off. If the leaf it lands
+     * on has been emptied by removals we settle for the nearest key after
+     * it, or failing that the last key in the tree.
+     */
+    pub fn approx_median_key(&self) -> Option<&K> {
+        let root = self.root.as_ref()?;
+        let mut node = root;
+        let mut lower = None;
+        let mut target = 0.5;
+
+        loop {
+            match **node {
+                BPlusNode::Leaf(ref leaf) => {
+                    if let Some(k) = leaf.keys.get((target * leaf.keys.len() as f64) as usize) {
+                        return Some(k);
+                    }
+
+                    let after = lower.map_or(Bound::Unbounded, Bound::Included);
+
+                    return Self::first_from(root, after)
+                        .or_else(|| Self::last_until(root, Bound::Unbounded))
+                        .map(|(k, _)| k);
+                },
+                BPlusNode::Interior(ref interior) => {
+                    let scaled = target * interior.children.len() as f64;
+                    let i = (scaled as usize).min(interior.children.len() - 1);
+
+                    if i > 0 {
+                        lower = Some(&interior.keys[i - 1]);
+                    }
+
+                    target = scaled - i as f64;
+                    node = &interior.children[i];
+                }
+            }
+        }
+    }
+
+    /*
+     * The depth of the deepest interior node that both keys descend
+     * through, with the root at depth 0. Two keys in the same leaf get the
+     * depth of that leaf's parent. The keys don't have to be in the tree,
+     * this only follows the paths a lookup would take. A tree that is a
+     * single leaf reports the root.
+     */
+    pub fn lca_depth(&self, a: &K, b: &K) -> Option<usize> {
+        let mut node = self.root.as_ref()?;
+        let mut depth = 0;
+
+        while let BPlusNode::Interior(ref interior) = **node {
+            let i = interior.child_index(a);
+
+            if i != interior.child_index(b) {
+                return Some(depth);
+            }
+
+            node = &interior.children[i];
+
+            if let BPlusNode::Leaf(_) = **node {
+                return Some(depth);
+            }
+
+            depth += 1;
+        }
+
+        Some(0)
     }
-    
-    fn insert(&mut self, key: &K, value: &V) {
+
+    pub fn insert(&mut self, key: &K, value: &V) {
         /* If the root doesn't exist yet allocate an empty leaf */
         if self.root.is_none() {
             self.root = Some(Rc::new(BPlusNode::Leaf(BPlusLeaf {
@@ -67,76 +813,3392 @@ impl<K: Ord + Copy, V: Copy> BPlusTree<K, V> {
             })));
         }
 
-        let mut root = self.root.as_mut().unwrap();
-        let root = Rc::get_mut(&mut root).expect("Someone else is borrowing our root");
+        self.min_key = Some(self.min_key.map_or(*key, |k| cmp::min(k, *key)));
+        self.max_key = Some(self.max_key.map_or(*key, |k| cmp::max(k, *key)));
 
-        /* Insert the key / value into the leaf */
-        match root {
-            BPlusNode::Interior(ref mut interior) => {
-                //TODO: implement interior nodes
-                panic!("This also can't happen yet")
-            },
+        let (added, split) = Self::insert_into(self.root.as_mut().unwrap(), key, value, self.split_ratio, &mut self.splits);
+
+        if added {
+            self.size += 1;
+        }
+
+        /* The root itself split, so the tree grows a level */
+        if let Some((k, sibling)) = split {
+            let root = self.root.take().unwrap();
+
+            self.root = Some(Rc::new(BPlusNode::Interior(BPlusInterior {
+                parent: None,
+                keys: vec![k],
+                children: vec![root, sibling]
+            })));
+        }
+    }
+
+    /*
+     * Puts the entry into the leaf it belongs in and splits whatever
+     * overflows on the way back up. Returns whether the key was new, plus
+     * the separator and new right sibling when this node had to split.
+     * New nodes don't point back up at their parent: a Weak to it would
+     * make Rc::get_mut fail on the parent for every later insert.
+     */
+    fn insert_into(node: &mut Rc<BPlusNode<K, V>>, key: &K, value: &V, ratio: f64, splits: &mut u64) -> (bool, Split<K, V>) {
+        match *Rc::get_mut(node).expect("Someone else is borrowing our node") {
             BPlusNode::Leaf(ref mut leaf) => {
-                if leaf.keys.len() >= 4 {
-                    //TODO: implement node splitting + tree growth
-                    let mut left = BPlusLeaf {
-                        parent: None,
-                        keys: Vec::new(),
-                        values: Vec::new(),
-                    };
-
-                    let mut right = BPlusLeaf {
-                        parent: None,
-                        keys: Vec::new(),
-                        values: Vec::new(),
-                    };
-
-                    for i in 0..(leaf.keys.len()/2) {
-                        left.keys.push(leaf.keys[i]);
-                        left.values.push(leaf.values[i]);
+                /*
+                 * Keep the leaf sorted so lookups can binary search it. An
+                 * existing key just gets its value overwritten.
+                 */
+                let added = match leaf.keys.binary_search(key) {
+                    Ok(i) => {
+                        leaf.values[i] = *value;
+                        false
+                    },
+                    Err(i) => {
+                        leaf.keys.insert(i, *key);
+                        leaf.values.insert(i, *value);
+                        true
                     }
+                };
 
-                    for i in (leaf.keys.len()/2)..leaf.keys.len() {
-                        right.keys.push(leaf.keys[i]);
-                        right.values.push(leaf.values[i]);
-                    }
+                if leaf.keys.len() <= MAX_KEYS {
+                    return (added, None);
+                }
 
-                    let left = Rc::new(BPlusNode::Leaf(left));
-                    let right = Rc::new(BPlusNode::Leaf(right));
+                let mid = Self::split_point(leaf.keys.len(), ratio);
+                let keys = leaf.keys.split_off(mid);
+                let values = leaf.values.split_off(mid);
+                *splits += 1;
 
-                    let inner = BPlusNode::Interior(BPlusInterior {
-                        parent: leaf.parent.clone(),
-                        keys: Vec::new(),
-                        children: vec![left, right]
-                    });
+                (added, Some((keys[0], Rc::new(BPlusNode::Leaf(BPlusLeaf { parent: None, keys, values })))))
+            },
+            BPlusNode::Interior(ref mut interior) => {
+                let i = interior.child_index(key);
+                let (added, split) = Self::insert_into(&mut interior.children[i], key, value, ratio, splits);
 
+                if let Some((k, sibling)) = split {
+                    interior.keys.insert(i, k);
+                    interior.children.insert(i + 1, sibling);
                 }
 
-                leaf.keys.push(key.clone());
-                leaf.values.push(value.clone());
+                (added, Self::split_interior(interior, ratio, splits))
             }
         }
     }
-}
 
-/************************* TESTING PROGRAM *************************/
-#[cfg(test)]
-mod tests {
-    use BPlusTree;
+    /*
+     * Splits an interior node with too many children in two, handing back
+     * the separator that moves up and the new right half. The separator
+     * between the halves moves up rather than being copied, since interior
+     * keys only steer lookups.
+     */
+    fn split_interior(interior: &mut BPlusInterior<K, V>, ratio: f64, splits: &mut u64) -> Split<K, V> {
+        if interior.children.len() <= MAX_KEYS + 1 {
+            return None;
+        }
 
-    #[test]
-    fn test_new() {
-        let bpt = BPlusTree::<u64, u64>::new();
-    }
+        *splits += 1;
 
-    #[test]
-    fn test_insert() {
-        let mut bpt = BPlusTree::<u64, u64>::new();
+        let mid = Self::split_point(interior.children.len(), ratio);
+        let children = interior.children.split_off(mid);
+        let mut keys = interior.keys.split_off(mid - 1);
+        let up = keys.remove(0);
 
-        let k = 7 as u64;
-        let v = 14 as u64;
+        Some((up, Rc::new(BPlusNode::Interior(BPlusInterior { parent: None, keys, children }))))
+    }
+
+    /* How many of len entries (or children) stay on the left, leaving at least one on each side */
+    fn split_point(len: usize, ratio: f64) -> usize {
+        ((len as f64 * ratio) as usize).clamp(1, len - 1)
+    }
+
+    /*
+     * Sets how a full node divides when insert splits it: the left half
+     * keeps this share of the entries and the right half gets the rest.
+     * The default of 0.5 splits evenly. Sequential inserts leave every
+     * left half behind for good, so a ratio near 1 packs them tighter.
+     * Panics unless 0 < ratio < 1.
+     */
+    pub fn set_split_ratio(&mut self, ratio: f64) {
+        assert!(ratio > 0.0 && ratio < 1.0, "split_ratio must be strictly between 0 and 1, got {}", ratio);
+        self.split_ratio = ratio;
+    }
+
+    pub fn split_ratio(&self) -> f64 {
+        self.split_ratio
+    }
+
+    /*
+     * How many node splits and merges the tree has gone through, as
+     * (splits, merges). Leaves and interior nodes both count, one per
+     * node that splits, so a root split counts once even though it also
+     * adds a level. The only merges are truncate folding a short node
+     * into its left neighbour; removals leave nodes short rather than
+     * merging them. Bulk loads build nodes whole and count as neither.
+     */
+    pub fn restructure_counts(&self) -> (u64, u64) {
+        (self.splits, self.merges)
+    }
+
+    /*
+     * Inserts like insert, but also returns the key's position in key
+     * order and the value it replaced, if any. Nodes keep no subtree
+     * counts, so the position comes from stepping over every entry before
+     * the key, which is O(n) rather than O(log n).
+     */
+    pub fn insert_full(&mut self, key: K, value: V) -> (usize, Option<V>) {
+        let rank = self.range_entries((Bound::Unbounded, Bound::Excluded(key))).count();
+        let old = self.get(&key).copied();
+
+        self.insert(&key, &value);
+        (rank, old)
+    }
+
+    /*
+     * Inserts and returns the key's position in key order. Overwriting an
+     * existing key leaves its position as it was. Costs O(n) for the same
+     * reason as insert_full.
+     */
+    pub fn insert_at(&mut self, key: K, value: V) -> usize {
+        self.insert_full(key, value).0
+    }
+
+    /*
+     * Inserts every pair, or none of them. The whole batch is checked up
+     * front, both for keys repeated inside the batch and for keys that are
+     * already in the tree, and the first offending key is returned.
+     */
+    pub fn insert_unique_batch(&mut self, pairs: Vec<(K, V)>) -> Result<(), BPlusError<K>> {
+        let mut keys: Vec<K> = pairs.iter().map(|&(k, _)| k).collect();
+        keys.sort();
+
+        if let Some(w) = keys.windows(2).find(|w| w[0] == w[1]) {
+            return Err(BPlusError::DuplicateKey(w[0]));
+        }
+
+        if let Some(k) = keys.iter().find(|k| self.contains_key(k)) {
+            return Err(BPlusError::DuplicateKey(*k));
+        }
+
+        for (k, v) in pairs {
+            self.insert(&k, &v);
+        }
+
+        Ok(())
+    }
+
+    /*
+     * The reference points straight into the leaf, so it borrows the
+     * whole tree. It stays valid until the next call that takes &mut self
+     * (insert, drain_range, truncate and so on), and the borrow checker
+     * won't let such a call happen while the reference is still alive.
+     * Since V is Copy, copy the value out if it has to outlive a mutation.
+     */
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let leaf = self.find_leaf(key)?;
+
+        leaf.keys.binary_search(key).ok().map(|i| &leaf.values[i])
+    }
+
+    /*
+     * Swaps in the new value only if the current one matches expected. An
+     * expected value of None means the key must be absent, and a new value
+     * of None removes the key. On failure the caller gets back both what is
+     * actually stored and the value we refused, so it can retry.
+     */
+    pub fn compare_and_swap(&mut self, key: &K, expected: Option<&V>, new: Option<V>) -> Result<(), CasError<V>>
+        where V: PartialEq
+    {
+        let current = self.get(key).cloned();
+
+        if current.as_ref() != expected {
+            return Err(CasError { current, rejected: new });
+        }
+
+        match new {
+            Some(v) => self.insert(key, &v),
+            None => {
+                self.remove_entry(key);
+            }
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Replaces the value for an existing key, but only if cond approves of
+     * the current value, and returns the old value. If the key is missing
+     * or cond says no, nothing changes and the new value is handed back.
+     */
+    pub fn replace_if<F: FnOnce(&V) -> bool>(&mut self, key: &K, new: V, cond: F) -> Result<Option<V>, V> {
+        let slot = match self.find_leaf_mut(key) {
+            Some(leaf) => match leaf.keys.binary_search(key) {
+                Ok(i) => &mut leaf.values[i],
+                Err(_) => return Err(new)
+            },
+            None => return Err(new)
+        };
+
+        if !cond(slot) {
+            return Err(new);
+        }
+
+        Ok(Some(mem::replace(slot, new)))
+    }
+
+    /*
+     * Applies a diff, e.g. one computed between two replicas, to bring
+     * this tree in line with the tree the diff was taken against.
+     */
+    pub fn apply_diff<I: Iterator<Item = Diff<K, V>>>(&mut self, diff: I) {
+        for change in diff {
+            match change {
+                Diff::Added(k, v) | Diff::Changed(k, _, v) => self.insert(&k, &v),
+                Diff::Removed(k, _) => {
+                    self.remove_entry(&k);
+                }
+            }
+        }
+    }
+
+    /*
+     * Inserts every entry of source whose key is in the range. The entries
+     * arrive in key order, and where a key is in both trees the value from
+     * source wins, just like inserting them one at a time.
+     */
+    pub fn copy_range_from<R: RangeBounds<K>>(&mut self, source: &BPlusTree<K, V>, range: R) {
+        for (k, v) in source.range_entries(Self::owned_bounds(&range)) {
+            self.insert(k, v);
+        }
+    }
+
+    /*
+     * Removes every entry in the range and hands them back in key order.
+     * The whole range is gone from the tree as soon as this returns, so
+     * dropping the iterator part way through still removes everything.
+     * Leaves the drain empties are cut out of the tree along with their
+     * separators, but leaves and interior nodes that are merely left short
+     * stay as they are rather than being merged with their neighbours.
+     */
+    pub fn drain_range<R: RangeBounds<K>>(&mut self, range: R) -> DrainRange<K, V> {
+        let drained: Vec<(K, V)> = self.range_entries(Self::owned_bounds(&range)).map(|(k, v)| (*k, *v)).collect();
+
+        for &(k, _) in &drained {
+            self.remove_entry(&k);
+        }
+
+        if !drained.is_empty() {
+            self.prune_empty();
+        }
+
+        DrainRange { entries: drained.into_iter() }
+    }
+
+    /*
+     * Cuts every empty leaf out of the tree, then drops interior roots
+     * that are left with a single child so the tree gets shorter instead
+     * of keeping a chain of one child nodes.
+     */
+    fn prune_empty(&mut self) {
+        let empty = match self.root {
+            Some(ref mut root) => Self::prune_empty_node(Rc::get_mut(root).expect("Someone else is borrowing our root")),
+            None => return
+        };
+
+        if empty {
+            self.root = None;
+            return;
+        }
+
+        loop {
+            let root = self.root.as_mut().unwrap();
+            let child = match *Rc::get_mut(root).expect("Someone else is borrowing our root") {
+                BPlusNode::Interior(ref mut interior) if interior.children.len() == 1 => interior.children.pop().unwrap(),
+                _ => break
+            };
+
+            *root = child;
+        }
+    }
+
+    /*
+     * Removes the empty children under this node and returns whether the
+     * node itself is now empty. Each child that goes takes a separator
+     * with it: the one to its left, or the one to its right if it was the
+     * first child. Either way its neighbour just covers the gap it leaves.
+     */
+    fn prune_empty_node(node: &mut BPlusNode<K, V>) -> bool {
+        match *node {
+            BPlusNode::Leaf(ref leaf) => leaf.keys.is_empty(),
+            BPlusNode::Interior(ref mut interior) => {
+                let mut i = 0;
+
+                while i < interior.children.len() {
+                    let child = Rc::get_mut(&mut interior.children[i]).expect("Someone else is borrowing this node");
+
+                    if Self::prune_empty_node(child) {
+                        interior.children.remove(i);
+
+                        if !interior.keys.is_empty() {
+                            interior.keys.remove(i.saturating_sub(1));
+                        }
+                    } else {
+                        i += 1;
+                    }
+                }
+
+                interior.children.is_empty()
+            }
+        }
+    }
+
+    /*
+     * Joins a tree whose keys are all above ours onto the right of this
+     * one. Rather than moving entries around, the shorter tree's root is
+     * hung off the taller tree's spine at the level where the heights
+     * line up, so only the nodes along that one path are touched. The
+     * separator is always higher's smallest key: it's what divides the
+     * two trees wherever the graft lands.
+     *
+     * Removals don't tidy up separators, so a spine can still carry one
+     * from a key that is long gone, past the new separator. Grafting
+     * there would leave the separators out of order, so in that case
+     * both trees are rebuilt into one from their entries instead.
+     */
+    pub fn concat(&mut self, mut higher: BPlusTree<K, V>) -> Result<(), BPlusError<K>> {
+        let sep = match higher.min_key {
+            Some(k) => k,
+            None => return Ok(())
+        };
+
+        if self.max_key.is_some_and(|max| max >= sep) {
+            return Err(BPlusError::Overlap(sep));
+        }
+
+        if self.is_empty() {
+            self.adopt(higher);
+            return Ok(());
+        }
+
+        let spines_fit = Self::spine_below(self.root.as_ref().unwrap(), &sep) && Self::spine_above(higher.root.as_ref().unwrap(), &sep);
+
+        if !spines_fit {
+            let pairs = self.entries().chain(higher.entries()).map(|(k, v)| (*k, *v)).collect();
+
+            self.adopt(Self::from_sorted_vec(pairs));
+            return Ok(());
+        }
+
+        let mut low = self.root.take().unwrap();
+        let mut high = higher.root.take().unwrap();
+        let (low_height, high_height) = (Self::height(&low), Self::height(&high));
+
+        let (mut root, split) = if low_height == high_height {
+            (low, Some((sep, high)))
+        } else if low_height > high_height {
+            let split = Self::graft(&mut low, low_height - high_height - 1, sep, high, true, self.split_ratio, &mut self.splits);
+            (low, split)
+        } else {
+            let split = Self::graft(&mut high, high_height - low_height - 1, sep, low, false, self.split_ratio, &mut self.splits);
+            (high, split)
+        };
+
+        if let Some((k, sibling)) = split {
+            root = Rc::new(BPlusNode::Interior(BPlusInterior {
+                parent: None,
+                keys: vec![k],
+                children: vec![root, sibling]
+            }));
+        }
+
+        self.root = Some(root);
+        self.size += higher.size;
+        self.max_key = higher.max_key;
+
+        Ok(())
+    }
+
+    /*
+     * How many levels a packed tree of n entries needs, where order is the
+     * most children an interior node can have and a leaf holds one less
+     * entry than that (so this tree is order MAX_KEYS + 1). That's the
+     * height a bulk load gives, and roughly ceil(log_order(n)).
+     */
+    pub fn expected_height(order: usize, n: usize) -> usize {
+        assert!(order >= 2, "a node needs room for at least two children");
+
+        if n == 0 {
+            return 0;
+        }
+
+        let mut nodes = n.div_ceil(order - 1);
+        let mut height = 1;
+
+        while nodes > 1 {
+            nodes = nodes.div_ceil(order);
+            height += 1;
+        }
+
+        height
+    }
+
+    /* Takes over another tree's entries, keeping this tree's settings and counters */
+    fn adopt(&mut self, other: BPlusTree<K, V>) {
+        self.root = other.root;
+        self.min_key = other.min_key;
+        self.max_key = other.max_key;
+        self.size = other.size;
+    }
+
+    /* Whether every separator down the right spine is below sep */
+    fn spine_below(mut node: &Rc<BPlusNode<K, V>>, sep: &K) -> bool {
+        while let BPlusNode::Interior(ref interior) = **node {
+            if interior.keys.last().is_some_and(|k| k >= sep) {
+                return false;
+            }
+
+            node = &interior.children[interior.children.len() - 1];
+        }
+
+        true
+    }
+
+    /* Whether every separator down the left spine is above sep */
+    fn spine_above(mut node: &Rc<BPlusNode<K, V>>, sep: &K) -> bool {
+        while let BPlusNode::Interior(ref interior) = **node {
+            if interior.keys.first().is_some_and(|k| k <= sep) {
+                return false;
+            }
+
+            node = &interior.children[0];
+        }
+
+        true
+    }
+
+    /* How many levels there are from this node down to the leaves */
+    fn height(mut node: &Rc<BPlusNode<K, V>>) -> usize {
+        let mut height = 1;
+
+        while let BPlusNode::Interior(ref interior) = **node {
+            node = &interior.children[0];
+            height += 1;
+        }
+
+        height
+    }
+
+    /*
+     * Does the work for concat. Walks down the right (or left) spine for
+     * levels more steps and adds subtree as the last (or first) child
+     * there. A node that ends up with too many children splits in two,
+     * and the new right half is handed back up for the parent to adopt.
+     */
+    fn graft(node: &mut Rc<BPlusNode<K, V>>, levels: usize, sep: K, subtree: Rc<BPlusNode<K, V>>, at_end: bool, ratio: f64, splits: &mut u64) -> Split<K, V> {
+        let interior = match *Rc::get_mut(node).expect("Someone else is borrowing our node") {
+            BPlusNode::Interior(ref mut interior) => interior,
+            BPlusNode::Leaf(_) => unreachable!("grafts always land on an interior node")
+        };
+
+        if levels > 0 {
+            let i = if at_end { interior.children.len() - 1 } else { 0 };
+            let (k, sibling) = Self::graft(&mut interior.children[i], levels - 1, sep, subtree, at_end, ratio, splits)?;
+
+            interior.keys.insert(i, k);
+            interior.children.insert(i + 1, sibling);
+        } else if at_end {
+            interior.keys.push(sep);
+            interior.children.push(subtree);
+        } else {
+            interior.keys.insert(0, sep);
+            interior.children.insert(0, subtree);
+        }
+
+        Self::split_interior(interior, ratio, splits)
+    }
+
+    /*
+     * Pulls a single entry out of its leaf. Leaves are allowed to run low
+     * (or empty) since nothing merges them back together yet.
+     */
+    fn remove_entry(&mut self, key: &K) -> Option<V> {
+        let value = {
+            let leaf = self.find_leaf_mut(key)?;
+            let i = leaf.keys.binary_search(key).ok()?;
+
+            leaf.keys.remove(i);
+            leaf.values.remove(i)
+        };
+
+        self.size -= 1;
+
+        if self.min_key == Some(*key) || self.max_key == Some(*key) {
+            self.refresh_key_bounds();
+        }
+
+        Some(value)
+    }
+
+    /*
+     * The value stored under the key, inserting V::default() first if the
+     * key isn't there yet. Handy for grouping, e.g. counting into a slot
+     * per key. The leaf is looked up again after inserting, since a split
+     * may have moved the key into the new right half.
+     */
+    pub fn get_or_insert_default(&mut self, key: K) -> &mut V
+        where V: Default
+    {
+        if !self.contains_key(&key) {
+            self.insert(&key, &V::default());
+        }
+
+        let leaf = self.find_leaf_mut(&key).unwrap();
+        let i = leaf.keys.binary_search(&key).unwrap();
+        &mut leaf.values[i]
+    }
+
+    /*
+     * Looks up a batch of keys at once. The probes are visited in sorted
+     * order and we remember the separators that bounded the last leaf we
+     * descended to, so a run of probes that land in the same leaf only
+     * pays for one descent. Results come back in the caller's order.
+     */
+    pub fn get_many(&self, keys: &[K]) -> Vec<Option<&V>> {
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by(|&a, &b| keys[a].cmp(&keys[b]));
+
+        let mut results = vec![None; keys.len()];
+        let mut current: Option<BoundedLeaf<K, V>> = None;
+
+        for i in order {
+            let key = &keys[i];
+            let in_current = match current {
+                Some(ref b) => b.lower.is_none_or(|lo| lo <= key) && b.upper.is_none_or(|hi| key < hi),
+                None => false
+            };
+
+            if !in_current {
+                current = self.find_leaf_bounded(key);
+            }
+
+            if let Some(BoundedLeaf { leaf, .. }) = current {
+                results[i] = leaf.keys.binary_search(key).ok().map(|j| &leaf.values[j]);
+            }
+        }
+
+        results
+    }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /*
+     * Same answer as contains_key, but keys below the smallest or above the
+     * largest key in the tree are turned away without touching any nodes.
+     */
+    pub fn key_exists_fast(&self, key: &K) -> bool {
+        match (self.min_key, self.max_key) {
+            (Some(ref lo), Some(ref hi)) if lo <= key && key <= hi => self.contains_key(key),
+            _ => false
+        }
+    }
+
+    /*
+     * Drops every entry outside the range and returns how many went. Like
+     * truncate, this cuts along the two edges of the range: subtrees that
+     * lie wholly outside it are dropped and only counted, never visited
+     * entry by entry, and only the nodes along each cut get trimmed.
+     */
+    pub fn retain_range<R: RangeBounds<K>>(&mut self, range: R) -> usize {
+        let (start, end) = Self::owned_bounds(&range);
+        let before = |k: &K| match start {
+            Bound::Included(ref s) => k < s,
+            Bound::Excluded(ref s) => k <= s,
+            Bound::Unbounded => false
+        };
+        let after = |k: &K| match end {
+            Bound::Included(ref e) => k > e,
+            Bound::Excluded(ref e) => k >= e,
+            Bound::Unbounded => false
+        };
+
+        let removed = match self.root {
+            Some(ref mut root) => {
+                let root = Rc::get_mut(root).expect("Someone else is borrowing our root");
+                Self::cut_below(root, &before) + Self::cut_above(root, &after)
+            },
+            None => 0
+        };
+
+        if removed > 0 {
+            self.size -= removed;
+            self.prune_empty();
+            self.refresh_key_bounds();
+        }
+
+        removed
+    }
+
+    /* Removes the entries for which before holds from the left edge of the subtree, returning how many went */
+    fn cut_below<F: Fn(&K) -> bool>(node: &mut BPlusNode<K, V>, before: &F) -> usize {
+        match *node {
+            BPlusNode::Leaf(ref mut leaf) => {
+                let n = leaf.keys.partition_point(|k| before(k));
+
+                leaf.keys.drain(..n);
+                leaf.values.drain(..n);
+                n
+            },
+            BPlusNode::Interior(ref mut interior) => {
+                /* every child left of the first separator that isn't before lies wholly before */
+                let j = interior.keys.partition_point(|k| before(k));
+                let dropped: usize = interior.children.drain(..j).map(|child| Self::count_entries(&child)).sum();
+
+                interior.keys.drain(..j);
+
+                let child = Rc::get_mut(&mut interior.children[0]).expect("Someone else is borrowing this node");
+                dropped + Self::cut_below(child, before)
+            }
+        }
+    }
+
+    /* The mirror image of cut_below, removing the entries for which after holds from the right edge */
+    fn cut_above<F: Fn(&K) -> bool>(node: &mut BPlusNode<K, V>, after: &F) -> usize {
+        match *node {
+            BPlusNode::Leaf(ref mut leaf) => {
+                let n = leaf.keys.partition_point(|k| !after(k));
+                let cut = leaf.keys.len() - n;
+
+                leaf.keys.truncate(n);
+                leaf.values.truncate(n);
+                cut
+            },
+            BPlusNode::Interior(ref mut interior) => {
+                let j = interior.keys.partition_point(|k| !after(k));
+                let dropped: usize = interior.children.drain(j + 1..).map(|child| Self::count_entries(&child)).sum();
+
+                interior.keys.truncate(j);
+
+                let child = Rc::get_mut(&mut interior.children[j]).expect("Someone else is borrowing this node");
+                dropped + Self::cut_above(child, after)
+            }
+        }
+    }
+
+    /* How many entries are stored under the node */
+    fn count_entries(node: &BPlusNode<K, V>) -> usize {
+        match *node {
+            BPlusNode::Leaf(ref leaf) => leaf.keys.len(),
+            BPlusNode::Interior(ref interior) => interior.children.iter().map(|child| Self::count_entries(child)).sum()
+        }
+    }
+
+    /*
+     * Keeps only the len smallest entries. Whole subtrees to the right of
+     * the cut point are dropped without being visited, and only the nodes
+     * along the cut itself get trimmed. Interior nodes the cut leaves
+     * short are folded into their left neighbours, and a root left with a
+     * single child is dropped, so the result stays balanced.
+     */
+    pub fn truncate(&mut self, len: usize) {
+        if len == 0 {
+            self.root = None;
+        } else if let Some(ref mut root) = self.root {
+            let root = Rc::get_mut(root).expect("Someone else is borrowing our root");
+            Self::truncate_node(root, len, &mut self.splits, &mut self.merges);
+        }
+
+        self.size = cmp::min(self.size, len);
+        self.prune_empty();
+        self.refresh_key_bounds();
+    }
+
+    /* Recomputes min_key and max_key after entries have been removed */
+    fn refresh_key_bounds(&mut self) {
+        let bounds = match self.root {
+            Some(ref root) => (Self::first_from(root, Bound::Unbounded).map(|(k, _)| *k),
+                               Self::last_until(root, Bound::Unbounded).map(|(k, _)| *k)),
+            None => (None, None)
+        };
+
+        self.min_key = bounds.0;
+        self.max_key = bounds.1;
+    }
+
+    /* Trims the subtree down to at most keep entries, returning how many are left */
+    fn truncate_node(node: &mut BPlusNode<K, V>, keep: usize, splits: &mut u64, merges: &mut u64) -> usize {
+        match *node {
+            BPlusNode::Leaf(ref mut leaf) => {
+                leaf.keys.truncate(keep);
+                leaf.values.truncate(keep);
+                leaf.keys.len()
+            },
+            BPlusNode::Interior(ref mut interior) => {
+                let mut kept = 0;
+                let mut i = 0;
+
+                while i < interior.children.len() && kept < keep {
+                    let child = Rc::get_mut(&mut interior.children[i]).expect("Someone else is borrowing this node");
+                    kept += Self::truncate_node(child, keep - kept, splits, merges);
+                    i += 1;
+                }
+
+                interior.children.truncate(i);
+                interior.keys.truncate(i.saturating_sub(1));
+                Self::mend_last_child(interior, splits, merges);
+                kept
+            }
+        }
+    }
+
+    /*
+     * The cut can leave the last child of an interior node with too few
+     * children of its own. Folding it into its left sibling fixes that,
+     * and if the two together are too many for one node they split back
+     * into two halves that are both at least half full.
+     */
+    fn mend_last_child(interior: &mut BPlusInterior<K, V>, splits: &mut u64, merges: &mut u64) {
+        let n = interior.children.len();
+        let short = match *interior.children[n - 1] {
+            BPlusNode::Interior(ref last) => last.children.len() < (MAX_KEYS + 2) / 2,
+            BPlusNode::Leaf(_) => false
+        };
+
+        if !short || n < 2 {
+            return;
+        }
+
+        let last = match Rc::try_unwrap(interior.children.pop().unwrap()).ok().expect("Someone else is borrowing this node") {
+            BPlusNode::Interior(last) => last,
+            BPlusNode::Leaf(_) => unreachable!("checked above")
+        };
+        let sep = interior.keys.pop().unwrap();
+        *merges += 1;
+
+        let split = match *Rc::get_mut(&mut interior.children[n - 2]).expect("Someone else is borrowing this node") {
+            BPlusNode::Interior(ref mut left) => {
+                left.keys.push(sep);
+                left.keys.extend(last.keys);
+                left.children.extend(last.children);
+
+                /* the short node's own last child may have been short too, with nothing to its left until now */
+                Self::mend_last_child(left, splits, merges);
+                Self::split_interior(left, 0.5, splits)
+            },
+            BPlusNode::Leaf(_) => unreachable!("siblings are at the same depth")
+        };
+
+        if let Some((k, sibling)) = split {
+            interior.keys.push(k);
+            interior.children.push(sibling);
+        }
+    }
+
+    /* Calls f on every entry in key order, letting it change the value in place */
+    pub fn for_each_mut<F: FnMut(&K, &mut V)>(&mut self, mut f: F) {
+        if let Some(ref mut root) = self.root {
+            Self::for_each_mut_node(Rc::get_mut(root).expect("Someone else is borrowing our root"), &mut f);
+        }
+    }
+
+    fn for_each_mut_node<F: FnMut(&K, &mut V)>(node: &mut BPlusNode<K, V>, f: &mut F) {
+        match *node {
+            BPlusNode::Leaf(ref mut leaf) => {
+                for (k, v) in leaf.keys.iter().zip(leaf.values.iter_mut()) {
+                    f(k, v);
+                }
+            },
+            BPlusNode::Interior(ref mut interior) => {
+                for child in &mut interior.children {
+                    Self::for_each_mut_node(Rc::get_mut(child).expect("Someone else is borrowing this node"), f);
+                }
+            }
+        }
+    }
+
+    /*
+     * Replaces the values of keys that are already in the tree and
+     * returns how many were replaced. Updates for missing keys are
+     * skipped, never inserted. The updates must be sorted by key, which
+     * lets one pass over the leaves line them up with the entries.
+     */
+    pub fn update_sorted<I: Iterator<Item = (K, V)>>(&mut self, updates: I) -> usize {
+        let mut updates = updates.peekable();
+        let mut hits = 0;
+
+        self.for_each_mut(|k, v| {
+            while updates.peek().is_some_and(|&(u, _)| u < *k) {
+                updates.next();
+            }
+
+            if let Some(&(u, new)) = updates.peek() {
+                if u == *k {
+                    *v = new;
+                    hits += 1;
+                    updates.next();
+                }
+            }
+        });
+
+        hits
+    }
+
+    /*
+     * Returns true only if the key is stored in the tree and also falls
+     * within the given range. The range check is done first since it is
+     * cheap, so keys outside the range never cause a descent at all.
+     */
+    pub fn contains_key_in_range<R: RangeBounds<K>>(&self, key: &K, range: R) -> bool {
+        range.contains(key) && self.contains_key(key)
+    }
+
+    /* Walks from the root down to the leaf that would hold the key */
+    fn find_leaf(&self, key: &K) -> Option<&BPlusLeaf<K, V>> {
+        let mut node = self.root.as_ref()?;
+
+        loop {
+            match **node {
+                BPlusNode::Leaf(ref leaf) => return Some(leaf),
+                BPlusNode::Interior(ref interior) => {
+                    #[cfg(test)]
+                    self.descents.set(self.descents.get() + 1);
+
+                    node = &interior.children[interior.child_index(key)];
+                }
+            }
+        }
+    }
+
+    /* The mutable version of find_leaf */
+    fn find_leaf_mut(&mut self, key: &K) -> Option<&mut BPlusLeaf<K, V>> {
+        let mut node = Rc::get_mut(self.root.as_mut()?).expect("Someone else is borrowing our root");
+
+        loop {
+            node = match *node {
+                BPlusNode::Leaf(ref mut leaf) => return Some(leaf),
+                BPlusNode::Interior(ref mut interior) => {
+                    let i = interior.child_index(key);
+                    Rc::get_mut(&mut interior.children[i]).expect("Someone else is borrowing this node")
+                }
+            };
+        }
+    }
+
+    /*
+     * The copy-on-write version of find_leaf_mut. Any node on the way down
+     * that is shared with another tree gets cloned, so only the path from
+     * the root to the leaf is copied and every sibling stays shared.
+     *
+     * Every other mutator still goes through Rc::get_mut and panics on a
+     * node that is shared. So while another tree holds on to the old
+     * root, this is the only safe way to change this one. Until a snapshot
+     * type builds on it and switches those mutators over, it is only
+     * built for the tests.
+     */
+    #[cfg(test)]
+    pub(crate) fn clone_path_to_leaf(&mut self, key: &K) -> Option<&mut BPlusLeaf<K, V>> {
+        let mut node = Rc::make_mut(self.root.as_mut()?);
+
+        loop {
+            node = match *node {
+                BPlusNode::Leaf(ref mut leaf) => return Some(leaf),
+                BPlusNode::Interior(ref mut interior) => {
+                    let i = interior.child_index(key);
+                    Rc::make_mut(&mut interior.children[i])
+                }
+            };
+        }
+    }
+
+    /* Same as find_leaf, but also returns the separators on either side */
+    fn find_leaf_bounded(&self, key: &K) -> Option<BoundedLeaf<'_, K, V>> {
+        let mut node = self.root.as_ref()?;
+        let mut lower = None;
+        let mut upper = None;
+
+        loop {
+            match **node {
+                BPlusNode::Leaf(ref leaf) => return Some(BoundedLeaf { leaf, lower, upper }),
+                BPlusNode::Interior(ref interior) => {
+                    #[cfg(test)]
+                    self.descents.set(self.descents.get() + 1);
+
+                    let i = interior.child_index(key);
+
+                    if i > 0 {
+                        lower = Some(&interior.keys[i - 1]);
+                    }
+                    if i < interior.keys.len() {
+                        upper = Some(&interior.keys[i]);
+                    }
+
+                    node = &interior.children[i];
+                }
+            }
+        }
+    }
+
+    /*
+     * Returns the first and last entries in the range, or None if the range
+     * is empty. Each end is found with its own descent, so nothing in the
+     * middle of the range is ever visited.
+     */
+    pub fn range_endpoints<R: RangeBounds<K>>(&self, range: R) -> Option<(Entry<'_, K, V>, Entry<'_, K, V>)> {
+        let root = self.root.as_ref()?;
+        let first = Self::first_from(root, range.start_bound())?;
+        let last = Self::last_until(root, range.end_bound())?;
+
+        if first.0 > last.0 {
+            return None;
+        }
+
+        Some((first, last))
+    }
+
+    /*
+     * Returns the entry whose key is closest to the probe, looking at only
+     * the floor and ceiling of the probe. When both are the same distance
+     * away the lower key wins.
+     */
+    pub fn get_nearest(&self, key: &K) -> Option<Entry<'_, K, V>>
+        where K: KeyDistance
+    {
+        let root = self.root.as_ref()?;
+        let floor = Self::last_until(root, Bound::Included(key));
+        let ceiling = Self::first_from(root, Bound::Included(key));
+
+        match (floor, ceiling) {
+            (Some(f), Some(c)) => if key.distance(c.0) < key.distance(f.0) { Some(c) } else { Some(f) },
+            (f, c) => f.or(c)
+        }
+    }
+
+    /*
+     * Binary searches with a comparator over whole entries, in the style of
+     * slice::binary_search_by: f says whether an entry comes before
+     * (Less), at (Equal) or after (Greater) the target, and must be
+     * monotone in key order. Returns the first entry that isn't Less,
+     * which is the match or the entry the target would be inserted ahead
+     * of, or None if every entry is Less.
+     */
+    pub fn bisect_by<F: FnMut(&K, &V) -> cmp::Ordering>(&self, mut f: F) -> Option<Entry<'_, K, V>> {
+        Self::bisect_node(self.root.as_ref()?, &mut f)
+    }
+
+    /*
+     * Interior nodes only hold separator keys, not values, so each child
+     * is judged by its first entry. The answer is in the last child that
+     * starts out Less, or failing that it's the first entry of the child
+     * after it.
+     */
+    fn bisect_node<'a, F>(node: &'a BPlusNode<K, V>, f: &mut F) -> Option<Entry<'a, K, V>>
+        where F: FnMut(&K, &V) -> cmp::Ordering
+    {
+        match *node {
+            BPlusNode::Leaf(ref leaf) => {
+                let i = leaf.keys.iter().zip(&leaf.values).take_while(|&(k, v)| f(k, v) == cmp::Ordering::Less).count();
+                leaf.keys.get(i).map(|k| (k, &leaf.values[i]))
+            },
+            BPlusNode::Interior(ref interior) => {
+                let mut before = None;
+
+                for child in &interior.children {
+                    match Self::first_from(child, Bound::Unbounded) {
+                        Some((k, v)) if f(k, v) != cmp::Ordering::Less => {
+                            return before.and_then(|c| Self::bisect_node(c, f)).or(Some((k, v)));
+                        },
+                        Some(_) => before = Some(child),
+                        None => {}
+                    }
+                }
+
+                before.and_then(|c| Self::bisect_node(c, f))
+            }
+        }
+    }
+
+    /*
+     * Finds the smallest entry that is past the start bound. We descend
+     * into the child that would hold the bound, and only move on to the
+     * children to its right if everything in that child was too small.
+     */
+    fn first_from<'a>(node: &'a BPlusNode<K, V>, start: Bound<&K>) -> Option<(&'a K, &'a V)> {
+        match *node {
+            BPlusNode::Leaf(ref leaf) => {
+                let i = match start {
+                    Bound::Included(k) => leaf.keys.binary_search(k).unwrap_or_else(|i| i),
+                    Bound::Excluded(k) => leaf.keys.binary_search(k).map(|i| i + 1).unwrap_or_else(|i| i),
+                    Bound::Unbounded => 0
+                };
+
+                leaf.keys.get(i).map(|k| (k, &leaf.values[i]))
+            },
+            BPlusNode::Interior(ref interior) => {
+                let i = match start {
+                    Bound::Included(k) | Bound::Excluded(k) => interior.child_index(k),
+                    Bound::Unbounded => 0
+                };
+
+                interior.children[i..].iter().filter_map(|child| Self::first_from(child, start)).next()
+            }
+        }
+    }
+
+    /* The mirror image of first_from: the largest entry before the end bound */
+    fn last_until<'a>(node: &'a BPlusNode<K, V>, end: Bound<&K>) -> Option<(&'a K, &'a V)> {
+        match *node {
+            BPlusNode::Leaf(ref leaf) => {
+                let n = match end {
+                    Bound::Included(k) => leaf.keys.binary_search(k).map(|i| i + 1).unwrap_or_else(|i| i),
+                    Bound::Excluded(k) => leaf.keys.binary_search(k).unwrap_or_else(|i| i),
+                    Bound::Unbounded => leaf.keys.len()
+                };
+
+                if n == 0 {
+                    return None;
+                }
+
+                Some((&leaf.keys[n - 1], &leaf.values[n - 1]))
+            },
+            BPlusNode::Interior(ref interior) => {
+                let i = match end {
+                    Bound::Included(k) | Bound::Excluded(k) => interior.child_index(k),
+                    Bound::Unbounded => interior.children.len() - 1
+                };
+
+                interior.children[..=i].iter().rev().filter_map(|child| Self::last_until(child, end)).next()
+            }
+        }
+    }
+
+    /*
+     * Returns every entry whose key starts with the given prefix. Since
+     * tuples sort by their first element, all of the matches sit next to
+     * each other, so we only have to compare prefixes and never need to
+     * build MIN/MAX keys for the remaining elements. The same comparison
+     * against the separators takes us straight down to the first match.
+     */
+    pub fn prefix_range<'a>(&'a self, prefix: &'a K::Prefix) -> impl Iterator<Item = (&'a K, &'a V)> + 'a
+        where K: KeyPrefix
+    {
+        Cursor::seek(self.root.as_ref(), move |k: &K| k.prefix() < prefix)
+            .take_while(move |&(k, _)| k.prefix() == prefix)
+    }
+
+    /* Just the values of the entries in the range, in key order */
+    pub fn range_values<R: RangeBounds<K>>(&self, range: R) -> impl Iterator<Item = &V> {
+        self.range_entries(Self::owned_bounds(&range)).map(|(_, v)| v)
+    }
+
+    /*
+     * Clears out and fills it with the entries in the range, so a caller
+     * running lots of range queries can keep reusing one buffer.
+     */
+    pub fn range_into<'a, R: RangeBounds<K>>(&'a self, range: R, out: &mut Vec<Entry<'a, K, V>>) {
+        out.clear();
+        out.extend(self.range_entries(Self::owned_bounds(&range)));
+    }
+
+    /*
+     * Roughly how many bytes the entries in the range would take up on
+     * the wire, assuming each entry costs the in-memory size of its key
+     * and value. Use estimate_serialized_size_with when the real encoding
+     * differs from that.
+     */
+    pub fn estimate_serialized_size<R: RangeBounds<K>>(&self, range: R) -> usize {
+        self.estimate_serialized_size_with(range, |_, _| mem::size_of::<K>() + mem::size_of::<V>())
+    }
+
+    /* Same as above, but the caller decides what each entry costs */
+    pub fn estimate_serialized_size_with<R, F>(&self, range: R, mut entry_size: F) -> usize
+        where R: RangeBounds<K>, F: FnMut(&K, &V) -> usize
+    {
+        self.range_entries(Self::owned_bounds(&range)).map(|(k, v)| entry_size(k, v)).sum()
+    }
+
+    /*
+     * All of the entries in key order that fall within the bounds. This
+     * takes owned bounds rather than a RangeBounds so the iterator doesn't
+     * have to borrow the caller's range; see owned_bounds.
+     */
+    fn range_entries(&self, bounds: (Bound<K>, Bound<K>)) -> impl Iterator<Item = Entry<'_, K, V>> {
+        let (start, end) = bounds;
+        let before = move |k: &K| match start {
+            Bound::Included(ref s) => k < s,
+            Bound::Excluded(ref s) => k <= s,
+            Bound::Unbounded => false
+        };
+
+        Cursor::seek(self.root.as_ref(), before)
+            .take_while(move |&(k, _)| match end {
+                Bound::Included(ref e) => k <= e,
+                Bound::Excluded(ref e) => k < e,
+                Bound::Unbounded => true
+            })
+    }
+
+    /* Copies the keys out of a range's bounds */
+    fn owned_bounds<R: RangeBounds<K>>(range: &R) -> (Bound<K>, Bound<K>) {
+        (range.start_bound().cloned(), range.end_bound().cloned())
+    }
+
+    /*
+     * The smallest and largest values (not keys) among the entries in the
+     * range, found in a single pass. A range with one entry returns its
+     * value as both.
+     */
+    pub fn range_min_max<R: RangeBounds<K>>(&self, range: R) -> Option<(&V, &V)>
+        where V: Ord
+    {
+        self.range_entries(Self::owned_bounds(&range)).fold(None, |acc, (_, v)| match acc {
+            Some((lo, hi)) => Some((cmp::min(lo, v), cmp::max(hi, v))),
+            None => Some((v, v))
+        })
+    }
+
+    /*
+     * Whether any key lies strictly between lo and hi. This only has to
+     * find the first key after lo and compare it with hi, so it costs one
+     * descent no matter how many keys are in between.
+     */
+    pub fn has_entry_between(&self, lo: &K, hi: &K) -> bool {
+        self.root.as_ref()
+            .and_then(|root| Self::first_from(root, Bound::Excluded(lo)))
+            .is_some_and(|(k, _)| k < hi)
+    }
+
+    /*
+     * The n-th entry (counting from 0) within the range. Nodes keep no
+     * subtree counts, so the entries before it are stepped over one by
+     * one.
+     */
+    pub fn nth_in_range<R: RangeBounds<K>>(&self, range: R, n: usize) -> Option<Entry<'_, K, V>> {
+        self.range_entries(Self::owned_bounds(&range)).nth(n)
+    }
+
+    /* The first entry in the range, in key order, whose value passes the predicate */
+    pub fn find_in_range<R: RangeBounds<K>, P: FnMut(&V) -> bool>(&self, range: R, mut pred: P) -> Option<Entry<'_, K, V>> {
+        self.range_entries(Self::owned_bounds(&range)).find(|&(_, v)| pred(v))
+    }
+
+    /*
+     * Whether any entry in the range has a value that passes the
+     * predicate. Stops at the first one that does.
+     */
+    pub fn any_in_range<R: RangeBounds<K>, P: FnMut(&V) -> bool>(&self, range: R, mut pred: P) -> bool {
+        self.range_entries(Self::owned_bounds(&range)).any(|(_, v)| pred(v))
+    }
+
+    /*
+     * Adds up the keys themselves over the range, e.g. for ids or
+     * timestamps. There's no per-subtree sum to lean on, so this walks
+     * the leaves in the range.
+     */
+    pub fn sum_keys_in_range<R: RangeBounds<K>>(&self, range: R) -> K
+        where K: iter::Sum
+    {
+        self.range_entries(Self::owned_bounds(&range)).map(|(k, _)| *k).sum()
+    }
+
+    /* How many different values show up among the entries in the range */
+    pub fn count_distinct_values_in_range<R: RangeBounds<K>>(&self, range: R) -> usize
+        where V: Ord
+    {
+        self.range_entries(Self::owned_bounds(&range)).map(|(_, v)| v).collect::<BTreeSet<_>>().len()
+    }
+
+    /*
+     * How many entries have a value that passes the predicate. This goes
+     * over each leaf's value slice directly rather than through entries.
+     */
+    pub fn count_values<P: FnMut(&V) -> bool>(&self, mut pred: P) -> usize {
+        self.leaves().iter().map(|leaf| leaf.values.iter().filter(|v| pred(v)).count()).sum()
+    }
+
+    /*
+     * Keyset pagination: returns up to limit entries that come strictly
+     * after the cursor key, or from the start of the tree when there is no
+     * cursor, along with the cursor for the next page. The next cursor is
+     * the last key returned, or None once a page comes back short. Each
+     * page descends straight to the cursor, so it costs O(log n + limit)
+     * no matter how deep into the tree it is.
+     */
+    pub fn page(&self, after: Option<&K>, limit: usize) -> (Vec<Entry<'_, K, V>>, Option<&K>) {
+        let start = match after {
+            Some(k) => Bound::Excluded(*k),
+            None => Bound::Unbounded
+        };
+
+        let page: Vec<_> = self.range_entries((start, Bound::Unbounded)).take(limit).collect();
+        let next = if page.len() < limit || limit == 0 {
+            None
+        } else {
+            page.last().map(|&(k, _)| k)
+        };
+
+        (page, next)
+    }
+
+    /*
+     * Pagination in descending order, for newest-first listings: returns
+     * up to limit entries strictly before the cursor key, largest first,
+     * along with the cursor for the next (smaller) page.
+     */
+    pub fn page_desc(&self, before: Option<&K>, limit: usize) -> (Vec<Entry<'_, K, V>>, Option<&K>) {
+        let page: Vec<_> = Cursor::seek_back(self.root.as_ref(), |k| before.is_some_and(|b| k >= b))
+            .take(limit)
+            .collect();
+        let next = if page.len() < limit || limit == 0 {
+            None
+        } else {
+            page.last().map(|&(k, _)| k)
+        };
+
+        (page, next)
+    }
+
+    /*
+     * The same as page, but the cursor is wrapped in an opaque token. The
+     * token only remembers the last key returned, so entries inserted or
+     * removed between calls never cause skips or repeats: the next page
+     * simply starts after that key.
+     */
+    pub fn page_after(&self, token: Option<&PageToken<K>>, limit: usize) -> (Vec<Entry<'_, K, V>>, Option<PageToken<K>>) {
+        let (page, next) = self.page(token.map(|t| &t.last), limit);
+
+        (page, next.map(|k| PageToken { last: *k }))
+    }
+
+    /*
+     * Walks the tree depth first, left to right, reporting each node to
+     * the visitor. The root is at depth 0.
+     */
+    pub fn visit<T: TreeVisitor<K, V>>(&self, visitor: &mut T) {
+        if let Some(ref root) = self.root {
+            Self::visit_node(root, 0, visitor);
+        }
+    }
+
+    fn visit_node<T: TreeVisitor<K, V>>(node: &BPlusNode<K, V>, depth: usize, visitor: &mut T) {
+        match *node {
+            BPlusNode::Leaf(ref leaf) => visitor.leaf(depth, &leaf.keys, &leaf.values),
+            BPlusNode::Interior(ref interior) => {
+                visitor.enter_interior(depth, &interior.keys, interior.children.len());
+
+                for child in &interior.children {
+                    Self::visit_node(child, depth + 1, visitor);
+                }
+
+                visitor.exit_interior(depth);
+            }
+        }
+    }
+
+    /*
+     * Swaps two children of the interior node reached by following
+     * parent_path down from the root, then moves the separators in front
+     * of the swapped children to their new first keys. If the tree no
+     * longer validates the swap is undone and false comes back. Between
+     * non-empty siblings that is always the case; the swaps that stick
+     * move an emptied leaf around. Nothing outside the tests runs balance
+     * experiments yet, so this is only built for them.
+     */
+    #[cfg(test)]
+    pub(crate) fn swap_children(&mut self, parent_path: &[usize], i: usize, j: usize) -> bool {
+        let old_keys = {
+            let interior = self.interior_at(parent_path);
+            let old_keys = interior.keys.clone();
+
+            interior.children.swap(i, j);
+
+            for &c in [i, j].iter().filter(|&&c| c > 0) {
+                if let Some((k, _)) = Self::first_from(&interior.children[c], Bound::Unbounded) {
+                    interior.keys[c - 1] = *k;
+                }
+            }
+
+            old_keys
+        };
+
+        if self.validate() {
+            return true;
+        }
+
+        let interior = self.interior_at(parent_path);
+        interior.children.swap(i, j);
+        interior.keys = old_keys;
+        false
+    }
+
+    #[cfg(test)]
+    fn interior_at(&mut self, path: &[usize]) -> &mut BPlusInterior<K, V> {
+        let mut node = Rc::get_mut(self.root.as_mut().expect("the tree is empty")).expect("Someone else is borrowing our root");
+
+        for &c in path {
+            node = match *node {
+                BPlusNode::Interior(ref mut interior) => Rc::get_mut(&mut interior.children[c]).expect("Someone else is borrowing this node"),
+                BPlusNode::Leaf(_) => panic!("the path runs past a leaf")
+            };
+        }
+
+        match *node {
+            BPlusNode::Interior(ref mut interior) => interior,
+            BPlusNode::Leaf(_) => panic!("the path ends at a leaf")
+        }
+    }
+
+    /*
+     * Sums up the shape of the tree in one pass. It's healthy when every
+     * leaf is at the same depth and no interior node other than the root
+     * is under half full.
+     */
+    pub fn balance_report(&self) -> BalanceReport {
+        #[derive(Default)]
+        struct Shape {
+            leaf_depths: Vec<usize>,
+            fanouts: Vec<Vec<usize>>
+        }
+
+        impl<K, V> TreeVisitor<K, V> for Shape {
+            fn enter_interior(&mut self, depth: usize, _keys: &[K], child_count: usize) {
+                if self.fanouts.len() <= depth {
+                    self.fanouts.resize(depth + 1, Vec::new());
+                }
+
+                self.fanouts[depth].push(child_count);
+            }
+
+            fn leaf(&mut self, depth: usize, _keys: &[K], _values: &[V]) {
+                self.leaf_depths.push(depth);
+            }
+        }
+
+        let mut shape = Shape::default();
+        self.visit(&mut shape);
+
+        let all_fanouts = || shape.fanouts.iter().flatten().cloned();
+        let min_full = (MAX_KEYS + 2) / 2;
+        let underfull_interiors = shape.fanouts.iter().skip(1).flatten().filter(|&&c| c < min_full).count();
+
+        let min_leaf_depth = shape.leaf_depths.iter().cloned().min().unwrap_or(0);
+        let max_leaf_depth = shape.leaf_depths.iter().cloned().max().unwrap_or(0);
+
+        BalanceReport {
+            min_leaf_depth,
+            max_leaf_depth,
+            min_fanout: all_fanouts().min().unwrap_or(0),
+            max_fanout: all_fanouts().max().unwrap_or(0),
+            underfull_interiors,
+            is_healthy: min_leaf_depth == max_leaf_depth && underfull_interiors == 0
+        }
+    }
+
+    /*
+     * Every stored key that a lookup from the root would not route to the
+     * leaf it actually sits in, which can only happen if the separators
+     * and the key ordering have drifted apart. Such keys are invisible to
+     * get even though a scan still finds them. Meant for debugging.
+     */
+    pub fn detect_misplaced_keys(&self) -> Vec<K> {
+        let mut misplaced = Vec::new();
+
+        for leaf in self.leaves() {
+            for k in &leaf.keys {
+                if !self.find_leaf(k).is_some_and(|found| ptr::eq(found, leaf)) {
+                    misplaced.push(*k);
+                }
+            }
+        }
+
+        misplaced
+    }
+
+    /*
+     * Checks the structural invariants: keys are strictly ascending, every
+     * interior node has one more child than keys, every key sits between
+     * the separators above it, and the cached size and min/max keys agree
+     * with what is actually stored. Meant for tests and debugging.
+     */
+    pub fn validate(&self) -> bool {
+        let ordered = match self.root {
+            Some(ref root) => Self::validate_node(root, None, None),
+            None => true
+        };
+
+        let bounds = match self.root {
+            Some(ref root) => self.min_key == Self::first_from(root, Bound::Unbounded).map(|(k, _)| *k)
+                && self.max_key == Self::last_until(root, Bound::Unbounded).map(|(k, _)| *k),
+            None => self.min_key.is_none() && self.max_key.is_none()
+        };
+
+        ordered && bounds && self.size_counter_is_consistent()
+    }
+
+    /* Every key in the subtree has to be in [lower, upper) */
+    fn validate_node(node: &BPlusNode<K, V>, lower: Option<&K>, upper: Option<&K>) -> bool {
+        let in_bounds = |k: &K| lower.is_none_or(|lo| lo <= k) && upper.is_none_or(|hi| k < hi);
+
+        match *node {
+            BPlusNode::Leaf(ref leaf) => {
+                leaf.keys.len() == leaf.values.len()
+                    && leaf.keys.windows(2).all(|w| w[0] < w[1])
+                    && leaf.keys.iter().all(in_bounds)
+            },
+            BPlusNode::Interior(ref interior) => {
+                interior.keys.len() + 1 == interior.children.len()
+                    && interior.keys.windows(2).all(|w| w[0] < w[1])
+                    && interior.keys.iter().all(in_bounds)
+                    && interior.children.iter().enumerate().all(|(i, child)| {
+                        let lo = if i == 0 { lower } else { Some(&interior.keys[i - 1]) };
+                        let hi = if i == interior.keys.len() { upper } else { Some(&interior.keys[i]) };
+
+                        Self::validate_node(child, lo, hi)
+                    })
+            }
+        }
+    }
+
+    /*
+     * Counts the entries the slow way and compares against the size field
+     * that insert, remove and friends keep up to date.
+     */
+    pub(crate) fn size_counter_is_consistent(&self) -> bool {
+        self.entries().count() == self.size
+    }
+
+    /* Calls f on every node, parents before their children */
+    pub fn visit_preorder<F: FnMut(NodeRef<K, V>)>(&self, mut f: F) {
+        if let Some(ref root) = self.root {
+            Self::preorder(root, &mut f);
+        }
+    }
+
+    /* Calls f on every node, children before their parents */
+    pub fn visit_postorder<F: FnMut(NodeRef<K, V>)>(&self, mut f: F) {
+        if let Some(ref root) = self.root {
+            Self::postorder(root, &mut f);
+        }
+    }
+
+    fn preorder<F: FnMut(NodeRef<K, V>)>(node: &BPlusNode<K, V>, f: &mut F) {
+        f(NodeRef { node });
+
+        if let BPlusNode::Interior(ref interior) = *node {
+            for child in &interior.children {
+                Self::preorder(child, f);
+            }
+        }
+    }
+
+    fn postorder<F: FnMut(NodeRef<K, V>)>(node: &BPlusNode<K, V>, f: &mut F) {
+        if let BPlusNode::Interior(ref interior) = *node {
+            for child in &interior.children {
+                Self::postorder(child, f);
+            }
+        }
+
+        f(NodeRef { node });
+    }
+
+    pub fn node_count(&self) -> usize {
+        let mut count = 0;
+        self.visit_preorder(|_| count += 1);
+        count
+    }
+
+    /* Every node's id along with its keys, in id order */
+    pub fn nodes(&self) -> Vec<(NodeId, Vec<K>)> {
+        let mut nodes = Vec::new();
+
+        if let Some(ref root) = self.root {
+            Self::number_nodes(root, None, &mut 0, &mut |id, _, node| nodes.push((id, node.keys().to_vec())));
+        }
+
+        nodes
+    }
+
+    /* Every parent to child link, as pairs of the ids handed out by nodes */
+    pub fn edges(&self) -> Vec<(NodeId, NodeId)> {
+        let mut edges = Vec::new();
+
+        if let Some(ref root) = self.root {
+            Self::number_nodes(root, None, &mut 0, &mut |id, parent, _| {
+                if let Some(parent) = parent {
+                    edges.push((parent, id));
+                }
+            });
+        }
+
+        edges
+    }
+
+    /* A preorder walk that gives each node the next id and passes along its parent's */
+    fn number_nodes<F>(node: &BPlusNode<K, V>, parent: Option<NodeId>, next: &mut usize, f: &mut F)
+        where F: FnMut(NodeId, Option<NodeId>, NodeRef<K, V>)
+    {
+        let id = NodeId(*next);
+        *next += 1;
+        f(id, parent, NodeRef { node });
+
+        if let BPlusNode::Interior(ref interior) = *node {
+            for child in &interior.children {
+                Self::number_nodes(child, Some(id), next, f);
+            }
+        }
+    }
+
+    /*
+     * Resumes an in-order scan strictly after the given key, so a caller
+     * that saved the last key it saw can pick up where it left off.
+     */
+    pub fn iter_from<'a>(&'a self, cursor: &K) -> impl Iterator<Item = Entry<'a, K, V>> {
+        self.range_entries((Bound::Excluded(*cursor), Bound::Unbounded))
+    }
+
+    /* All of the entries in key order */
+    fn entries(&self) -> impl Iterator<Item = (&K, &V)> {
+        Cursor::seek(self.root.as_ref(), |_| false)
+    }
+
+    /* All of the leaves from left to right */
+    fn leaves(&self) -> Vec<&BPlusLeaf<K, V>> {
+        let mut leaves = Vec::new();
+
+        if let Some(ref root) = self.root {
+            Self::collect_leaves(root, &mut leaves);
+        }
+
+        leaves
+    }
+
+    fn collect_leaves<'a>(node: &'a BPlusNode<K, V>, leaves: &mut Vec<&'a BPlusLeaf<K, V>>) {
+        match *node {
+            BPlusNode::Leaf(ref leaf) => leaves.push(leaf),
+            BPlusNode::Interior(ref interior) => {
+                for child in &interior.children {
+                    Self::collect_leaves(child, leaves);
+                }
+            }
+        }
+    }
+}
+
+/*
+ * Where page_after left off. Callers should treat this as opaque; all it
+ * holds is the last key that was handed out.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PageToken<K> {
+    last: K
+}
+
+/*
+ * Tokens usually have to leave the process, e.g. as a cursor in an HTTP
+ * response, so they can be turned into bytes and back using the key's
+ * order-preserving encoding.
+ */
+impl<K: Encode> PageToken<K> {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.last.to_key()
+    }
+}
+
+impl<K: Decode> PageToken<K> {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        Ok(PageToken { last: K::from_key(bytes)? })
+    }
+}
+
+/*
+ * Callbacks for BPlusTree::visit. This is the one sanctioned way for code
+ * outside the tree (serializers, analyzers, pretty printers) to walk the
+ * nodes without poking at private fields. Interior callbacks default to
+ * doing nothing since plenty of visitors only care about the leaves.
+ */
+pub trait TreeVisitor<K, V> {
+    fn enter_interior(&mut self, _depth: usize, _keys: &[K], _child_count: usize) {}
+
+    fn leaf(&mut self, depth: usize, keys: &[K], values: &[V]);
+
+    fn exit_interior(&mut self, _depth: usize) {}
+}
+
+/*
+ * A structural health summary from BPlusTree::balance_report. A fanout is
+ * the number of children of an interior node, so both fanouts are 0 for a
+ * tree that is a single leaf.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BalanceReport {
+    pub min_leaf_depth: usize,
+    pub max_leaf_depth: usize,
+    pub min_fanout: usize,
+    pub max_fanout: usize,
+
+    /* Interior nodes, other than the root, under half full */
+    pub underfull_interiors: usize,
+
+    pub is_healthy: bool
+}
+
+/*
+ * Names a node in the output of nodes and edges. Nodes are numbered in
+ * preorder starting from the root at 0, so ids only mean anything until
+ * the tree is next changed.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(pub usize);
+
+/*
+ * A read-only view of a single node, handed out by the visit_* methods so
+ * callers can write their own structural analysis without us exposing the
+ * node types themselves.
+ */
+pub struct NodeRef<'a, K: Ord + Copy + 'a, V: Copy + 'a> {
+    node: &'a BPlusNode<K, V>
+}
+
+impl<'a, K: Ord + Copy, V: Copy> NodeRef<'a, K, V> {
+    pub fn is_leaf(&self) -> bool {
+        match *self.node {
+            BPlusNode::Leaf(_) => true,
+            BPlusNode::Interior(_) => false
+        }
+    }
+
+    /* The entry keys for a leaf, or the separator keys for an interior node */
+    pub fn keys(&self) -> &'a [K] {
+        match *self.node {
+            BPlusNode::Leaf(ref leaf) => &leaf.keys,
+            BPlusNode::Interior(ref interior) => &interior.keys
+        }
+    }
+
+    /* The values of a leaf, or None for an interior node */
+    pub fn values(&self) -> Option<&'a [V]> {
+        match *self.node {
+            BPlusNode::Leaf(ref leaf) => Some(&leaf.values),
+            BPlusNode::Interior(_) => None
+        }
+    }
+
+    /* The children of an interior node, or nothing for a leaf */
+    pub fn children(&self) -> Vec<NodeRef<'a, K, V>> {
+        match *self.node {
+            BPlusNode::Leaf(_) => Vec::new(),
+            BPlusNode::Interior(ref interior) => interior.children.iter().map(|child| NodeRef { node: child }).collect()
+        }
+    }
+}
+
+/*
+ * Keys that have a notion of how far apart two of them are. This is what
+ * get_nearest uses to choose between the floor and the ceiling.
+ */
+pub trait KeyDistance {
+    type Distance: Ord;
+
+    fn distance(&self, other: &Self) -> Self::Distance;
+}
+
+/*
+ * The difference between two integers always fits in the unsigned type of
+ * the same width, even for signed types, as long as we subtract the
+ * smaller from the larger and let it wrap.
+ */
+macro_rules! impl_key_distance {
+    ($($t:ty => $u:ty),*) => {
+        $(
+            impl KeyDistance for $t {
+                type Distance = $u;
+
+                fn distance(&self, other: &Self) -> $u {
+                    let (hi, lo) = if self >= other { (self, other) } else { (other, self) };
+                    hi.wrapping_sub(*lo) as $u
+                }
+            }
+        )*
+    }
+}
+
+impl_key_distance!(u8 => u8, u16 => u16, u32 => u32, u64 => u64, u128 => u128, usize => usize,
+                   i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128, isize => usize);
+
+/*
+ * Composite keys are just tuples, which already implement Ord. This trait
+ * pulls out the leading element so callers can scan every key that shares
+ * it, e.g. all of the (customer_id, order_ts) keys for one customer.
+ */
+pub trait KeyPrefix {
+    type Prefix: Ord;
+
+    fn prefix(&self) -> &Self::Prefix;
+}
+
+impl<A: Ord, B> KeyPrefix for (A, B) {
+    type Prefix = A;
+
+    fn prefix(&self) -> &A {
+        &self.0
+    }
+}
+
+impl<A: Ord, B, C> KeyPrefix for (A, B, C) {
+    type Prefix = A;
+
+    fn prefix(&self) -> &A {
+        &self.0
+    }
+}
+
+/*
+ * How many bytes a key points at outside of the tree's own nodes. The
+ * blanket impl covers &str and &[u8] style keys, which is where size_of
+ * says nothing about what the key really costs.
+ */
+pub trait KeySize {
+    fn key_size(&self) -> usize;
+}
+
+impl<T: AsRef<[u8]>> KeySize for T {
+    fn key_size(&self) -> usize {
+        self.as_ref().len()
+    }
+}
+
+/************************* TESTING PROGRAM *************************/
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+    use std::rc::Rc;
+    use {BPlusError, BPlusInterior, BPlusLeaf, BPlusNode, BPlusTree, CasError, Diff};
+    use {BalanceReport, KeyDistance, KeyPrefix, NodeId, PageToken, TreeVisitor, MAX_KEYS};
+    use keyenc::DecodeError;
+
+    /*
+     * Tests that need the leaves laid out just so build a two level tree
+     * by hand. Each inner Vec becomes a leaf and every key maps to ten
+     * times itself.
+     */
+    fn from_leaves(leaves: Vec<Vec<u64>>) -> BPlusTree<u64, u64> {
+        let keys = leaves[1..].iter().map(|leaf| leaf[0]).collect();
+        let children = leaves.into_iter().map(|keys| {
+            Rc::new(BPlusNode::Leaf(BPlusLeaf {
+                parent: None,
+                values: keys.iter().map(|k| k * 10).collect(),
+                keys,
+            }))
+        }).collect();
+
+        let mut tree = BPlusTree {
+            root: Some(Rc::new(BPlusNode::Interior(BPlusInterior { parent: None, keys, children }))),
+            ..BPlusTree::new()
+        };
+
+        tree.size = tree.entries().count();
+        tree.refresh_key_bounds();
+        tree
+    }
+
+    #[test]
+    fn test_new() {
+        let _bpt = BPlusTree::<u64, u64>::new();
+    }
+
+    #[test]
+    fn test_insert() {
+        let mut bpt = BPlusTree::<u64, u64>::new();
+
+        let k = 7_u64;
+        let v = 14_u64;
 
         bpt.insert(&k, &v);
+        assert_eq!(bpt.get(&k), Some(&v));
+
+        /* enough to split leaves, interior nodes and the root a few times over */
+        let mut x = 3_u64;
+        for i in 0..500 {
+            x = x.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let k = (x >> 33) % 300;
+
+            bpt.insert(&k, &i);
+            assert_eq!(bpt.get(&k), Some(&i));
+        }
+        assert!(bpt.validate());
+        assert!(bpt.entries().map(|(k, _)| k).collect::<Vec<_>>().windows(2).all(|w| w[0] < w[1]));
+
+        let report = bpt.balance_report();
+        assert_eq!(report.min_leaf_depth, report.max_leaf_depth);
+        assert!(report.min_leaf_depth > 2);
+        assert!(report.min_fanout >= 2 && report.max_fanout <= MAX_KEYS + 1);
+
+        /* a bulk loaded tree starts out with an interior root */
+        let mut bulk = BPlusTree::<u64, u64>::from_iter_last_wins((0..100).map(|k| (k * 3, k)));
+        for k in 0..300 {
+            bulk.insert(&k, &(k + 1));
+        }
+        assert_eq!(bulk.len(), 300);
+        assert!((0..300).all(|k| bulk.get(&k) == Some(&(k + 1))));
+        assert!(bulk.validate());
+    }
+
+    #[test]
+    fn test_insert_full() {
+        let mut bpt = BPlusTree::<u64, char>::new();
+
+        assert_eq!(bpt.insert_full(20, 'a'), (0, None));
+        assert_eq!(bpt.insert_full(10, 'b'), (0, None));
+        assert_eq!(bpt.insert_full(30, 'c'), (2, None));
+        assert_eq!(bpt.insert_full(20, 'd'), (1, Some('a')));
+        assert_eq!(bpt.insert_full(15, 'e'), (1, None));
+        assert_eq!(bpt.insert_full(30, 'f'), (3, Some('c')));
+
+        /* ranks keep lining up once the tree has a few levels */
+        for k in 0..100 {
+            assert_eq!(bpt.insert_full(100 + k * 2, 'x'), (4 + k as usize, None));
+        }
+        assert_eq!(bpt.insert_full(150, 'y'), (29, Some('x')));
+        assert_eq!(bpt.insert_full(151, 'y'), (30, None));
+
+        assert_eq!(bpt.len(), 105);
+        assert!(bpt.validate());
+    }
+
+    #[test]
+    fn test_insert_at() {
+        let mut bpt = BPlusTree::<u64, u64>::new();
+
+        assert_eq!(bpt.insert_at(50, 0), 0);
+        assert_eq!(bpt.insert_at(10, 0), 0);
+        assert_eq!(bpt.insert_at(90, 0), 2);
+        assert_eq!(bpt.insert_at(30, 0), 1);
+        assert_eq!(bpt.insert_at(70, 0), 3);
+
+        /* an overwrite keeps its rank and doesn't grow the tree */
+        assert_eq!(bpt.insert_at(30, 1), 1);
+        assert_eq!(bpt.get(&30), Some(&1));
+        assert_eq!(bpt.len(), 5);
+
+        /* out of order inserts across several leaves */
+        for k in (0..20).rev() {
+            assert_eq!(bpt.insert_at(100 + k, 0), 5);
+        }
+        assert_eq!(bpt.insert_at(110, 2), 15);
+        assert_eq!(bpt.insert_at(60, 0), 3);
+        assert!(bpt.validate());
+    }
+
+    #[test]
+    fn test_restructure_counts() {
+        let shape = |bpt: &BPlusTree<u64, u64>| {
+            let mut nodes = 0;
+            bpt.visit_preorder(|_| nodes += 1);
+            (nodes, bpt.root.as_ref().map_or(0, BPlusTree::height))
+        };
+
+        let mut bpt = BPlusTree::<u64, u64>::new();
+        let mut x = 11_u64;
+        let mut observed = 0;
+
+        /* each split adds one node, and every new level (the first leaf included) adds one more */
+        for _ in 0..600 {
+            x = x.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let (nodes, height) = shape(&bpt);
+            let (splits, _) = bpt.restructure_counts();
+
+            bpt.insert(&((x >> 33) % 400), &0);
+
+            let (new_nodes, new_height) = shape(&bpt);
+            let new_splits = bpt.restructure_counts().0;
+            let grew = (new_height - height) as u64;
+            assert_eq!(new_splits - splits, (new_nodes - nodes) as u64 - grew);
+            observed += new_splits - splits;
+        }
+        assert!(observed > 100);
+        assert_eq!(bpt.restructure_counts(), (observed, 0));
+
+        /* cutting the tree short folds nodes back together */
+        bpt.truncate(101);
+        assert!(bpt.restructure_counts().1 > 0);
+        assert!(bpt.balance_report().is_healthy);
+
+        /* bulk loads build whole nodes and count as neither */
+        assert_eq!(BPlusTree::from_sorted_vec((0..500_u64).map(|k| (k, k)).collect()).restructure_counts(), (0, 0));
+    }
+
+    #[test]
+    fn test_split_ratio() {
+        let leaf_sizes = |bpt: &BPlusTree<u64, u64>| bpt.leaves().iter().map(|leaf| leaf.keys.len()).collect::<Vec<_>>();
+
+        /* the first split of a full leaf keeps 80% of its five entries on the left */
+        let mut bpt = BPlusTree::<u64, u64>::new();
+        bpt.set_split_ratio(0.8);
+        for k in 0..MAX_KEYS as u64 + 1 {
+            bpt.insert(&k, &k);
+        }
+        assert_eq!(leaf_sizes(&bpt), vec![4, 1]);
+
+        /* sequential inserts leave the left halves behind, so they stay 80% full */
+        for k in 5..400 {
+            bpt.insert(&k, &k);
+        }
+        assert!(bpt.validate());
+        assert!(leaf_sizes(&bpt).iter().rev().skip(1).all(|&n| n == 4));
+
+        let mut even = BPlusTree::<u64, u64>::new();
+        for k in 0..400 {
+            even.insert(&k, &k);
+        }
+        assert!(leaf_sizes(&even).iter().rev().skip(1).all(|&n| n == 2));
+        assert!(bpt.utilization() > even.utilization());
+
+        /* the ratio carries over to a compacted copy */
+        assert_eq!(even.split_ratio(), 0.5);
+        assert_eq!(bpt.compact_into().split_ratio(), 0.8);
+
+        for &bad in &[0.0, 1.0, -0.5, 1.5, f64::NAN] {
+            assert!(::std::panic::catch_unwind(|| BPlusTree::<u64, u64>::new().set_split_ratio(bad)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_root_split() {
+        let mut bpt = BPlusTree::<u64, u64>::new();
+        for k in 0..MAX_KEYS as u64 {
+            bpt.insert(&k, &k);
+        }
+        assert!(matches!(**bpt.root.as_ref().unwrap(), BPlusNode::Leaf(_)));
+
+        /* one more and the leaf splits in two under a new interior root */
+        bpt.insert(&(MAX_KEYS as u64), &0);
+
+        match **bpt.root.as_ref().unwrap() {
+            BPlusNode::Interior(ref root) => {
+                assert_eq!(root.keys, vec![MAX_KEYS as u64 / 2]);
+                assert_eq!(root.children.len(), 2);
+            },
+            BPlusNode::Leaf(_) => panic!("the root should have split")
+        }
+
+        assert_eq!(bpt.len(), MAX_KEYS + 1);
+        assert!(bpt.validate());
+    }
+
+    #[test]
+    fn test_contains_key_in_range() {
+        let mut bpt = BPlusTree::<u64, u64>::new();
+
+        for k in 0..10 {
+            bpt.insert(&k, &(k * 2));
+        }
+
+        assert!(bpt.contains_key_in_range(&5, 3..8));
+        assert!(!bpt.contains_key_in_range(&5, 6..8));
+        assert!(!bpt.contains_key_in_range(&5, ..5));
+        assert!(!bpt.contains_key_in_range(&12, 0..20));
+    }
+
+    #[test]
+    fn test_prefix_range() {
+        let mut bpt = BPlusTree::<(u8, u32), u32>::new();
+
+        for a in [0, 1, 2, u8::MAX].iter() {
+            for b in [0, 5, u32::MAX].iter() {
+                bpt.insert(&(*a, *b), &b.wrapping_add(*a as u32));
+            }
+        }
+
+        for a in [0, 1, u8::MAX].iter() {
+            let expected: Vec<_> = bpt.entries()
+                .filter(|&(k, _)| (*a, 0) <= *k && *k <= (*a, u32::MAX))
+                .collect();
+            let found: Vec<_> = bpt.prefix_range(a).collect();
+
+            assert_eq!(found.len(), 3);
+            assert_eq!(found, expected);
+            assert!(found.iter().all(|&(k, _)| k.prefix() == a));
+        }
+
+        assert_eq!(bpt.prefix_range(&7).count(), 0);
+
+        /* matches that straddle leaves several levels down */
+        let deep = BPlusTree::<(u8, u32), u32>::from_iter_last_wins((0..20).flat_map(|a| (0..7).map(move |b| ((a, b), b))));
+        for a in 0..21 {
+            let found: Vec<_> = deep.prefix_range(&a).map(|(k, _)| *k).collect();
+            assert_eq!(found, (0..7).filter(|_| a < 20).map(|b| (a, b)).collect::<Vec<_>>());
+        }
+
+        let mut triples = BPlusTree::<(u8, u8, u8), ()>::new();
+        triples.insert(&(1, 2, 3), &());
+        triples.insert(&(1, 9, 0), &());
+        triples.insert(&(2, 0, 0), &());
+
+        assert_eq!(triples.prefix_range(&1).count(), 2);
+    }
+
+    #[test]
+    fn test_concat() {
+        struct Shape {
+            leaf_depths: Vec<usize>,
+            widest: usize
+        }
+
+        impl TreeVisitor<u64, u64> for Shape {
+            fn enter_interior(&mut self, _depth: usize, _keys: &[u64], child_count: usize) {
+                self.widest = self.widest.max(child_count);
+            }
+
+            fn leaf(&mut self, depth: usize, _keys: &[u64], _values: &[u64]) {
+                self.leaf_depths.push(depth);
+            }
+        }
+
+        let shard = |keys: std::ops::Range<u64>| BPlusTree::from_sorted_vec(keys.map(|k| (k, k * 10)).collect());
+
+        /* a tall tree and a short one, joined in both directions, and two of the same height */
+        for &(split, end) in &[(40, 45), (5, 200), (20, 40), (1, 2), (60, 61)] {
+            let mut bpt = shard(0..split);
+            bpt.concat(shard(split..end)).unwrap();
+
+            assert_eq!(bpt.entries().map(|(k, v)| (*k, *v)).collect::<Vec<_>>(), (0..end).map(|k| (k, k * 10)).collect::<Vec<_>>());
+            assert!(bpt.validate());
+
+            let mut shape = Shape { leaf_depths: Vec::new(), widest: 0 };
+            bpt.visit(&mut shape);
+            assert!(shape.leaf_depths.windows(2).all(|w| w[0] == w[1]));
+            assert!(shape.widest <= 5);
+
+            /* the joined tree takes inserts on either side of the seam */
+            bpt.insert(&end, &0);
+            bpt.insert(&split, &1);
+            assert_eq!(bpt.len() as u64, end + 1);
+            assert_eq!(bpt.get(&split), Some(&1));
+            assert!(bpt.validate());
+        }
+
+        /* clean spines graft in place, so the leaves are the same allocations */
+        let mut bpt = shard(0..100);
+        let first_leaf = bpt.leaves()[0].keys.as_ptr();
+        bpt.concat(shard(100..110)).unwrap();
+        assert_eq!(bpt.leaves()[0].keys.as_ptr(), first_leaf);
+
+        /* point removals leave stale separators on the spines of either tree */
+        let mut low = BPlusTree::<u64, u64>::new();
+        for k in 0..100 {
+            low.insert(&k, &(k * 10));
+        }
+        for k in 55..100 {
+            low.compare_and_swap(&k, Some(&(k * 10)), None).unwrap();
+        }
+
+        let mut high = shard(60..200);
+        for k in 60..150 {
+            high.compare_and_swap(&k, Some(&(k * 10)), None).unwrap();
+        }
+
+        let mut middle = shard(61..70);
+        middle.concat(high).unwrap();
+        assert!(middle.validate());
+        low.concat(middle).unwrap();
+
+        let expected: Vec<u64> = (0..55).chain(61..70).chain(150..200).collect();
+        assert_eq!(low.entries().map(|(k, _)| *k).collect::<Vec<_>>(), expected);
+        assert!(expected.iter().all(|k| low.get(k) == Some(&(k * 10))));
+        assert_eq!(low.len(), expected.len());
+        assert!(low.validate());
+
+        low.insert(&60, &0);
+        assert!(low.validate());
+
+        let mut bpt = shard(0..10);
+        assert_eq!(bpt.concat(shard(9..20)).err(), Some(BPlusError::Overlap(9)));
+        assert_eq!(bpt.len(), 10);
+
+        bpt.concat(BPlusTree::new()).unwrap();
+        let mut empty = BPlusTree::new();
+        empty.concat(bpt).unwrap();
+        assert_eq!(empty.len(), 10);
+        assert!(empty.validate());
+    }
+
+    #[test]
+    fn test_get_reference_lifetime() {
+        let mut bpt = BPlusTree::new();
+        bpt.insert(&1, &10);
+        bpt.insert(&2, &20);
+
+        /* any number of shared references can be held between mutations */
+        let (a, b) = (bpt.get(&1).unwrap(), bpt.get(&2).unwrap());
+        assert_eq!((*a, *b), (10, 20));
+
+        /* a copied value survives the mutation, the tree sees the new one */
+        let saved = *bpt.get(&1).unwrap();
+        bpt.insert(&1, &11);
+        assert_eq!(saved, 10);
+        assert_eq!(bpt.get(&1), Some(&11));
+
+        bpt.drain_range(1..2).count();
+        assert_eq!(bpt.get(&1), None);
+        assert_eq!(bpt.get(&2), Some(&20));
+    }
+
+    #[test]
+    fn test_nodes_and_edges() {
+        let bpt = BPlusTree::from_unsorted_iter((0..40_u64).map(|k| (k, k)));
+
+        let nodes = bpt.nodes();
+        let edges = bpt.edges();
+        assert_eq!(nodes.len(), bpt.node_count());
+        assert_eq!(edges.len(), bpt.node_count() - 1);
+        assert_eq!(nodes[0], (NodeId(0), vec![20]));
+
+        /* every node but the root has exactly one parent, which comes before it */
+        for (i, &(id, _)) in nodes.iter().enumerate().skip(1) {
+            let parents: Vec<_> = edges.iter().filter(|&&(_, child)| child == id).collect();
+            assert_eq!(parents.len(), 1);
+            assert!(parents[0].0 < id);
+            assert_eq!(id, NodeId(i));
+        }
+
+        let leaf_keys: Vec<u64> = nodes.iter()
+            .filter(|&&(id, _)| edges.iter().all(|&(parent, _)| parent != id))
+            .flat_map(|(_, keys)| keys.clone())
+            .collect();
+        assert_eq!(leaf_keys, (0..40).collect::<Vec<_>>());
+
+        let empty = BPlusTree::<u64, u64>::new();
+        assert_eq!((empty.nodes().len(), empty.edges().len(), empty.node_count()), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_get_or_insert_default() {
+        let mut groups = BPlusTree::<char, (u32, u32)>::new();
+
+        for word in &["apple", "avocado", "banana", "blueberry", "cherry", "apricot"] {
+            let group = groups.get_or_insert_default(word.chars().next().unwrap());
+            group.0 += 1;
+            group.1 += word.len() as u32;
+        }
+
+        assert_eq!(groups.entries().map(|(k, v)| (*k, *v)).collect::<Vec<_>>(),
+                   vec![('a', (3, 19)), ('b', (2, 15)), ('c', (1, 6))]);
+        assert_eq!(groups.len(), 3);
+        assert!(groups.validate());
+
+        /* the reference handed back points into whichever half the key lands in after a split */
+        let mut bpt = BPlusTree::<u64, u64>::new();
+        for k in 0..MAX_KEYS as u64 {
+            bpt.insert(&(k * 10), &k);
+        }
+
+        for &k in &[5, 45, 25, 1, 99, 12, 33] {
+            let splits = bpt.restructure_counts().0;
+            *bpt.get_or_insert_default(k) += 7;
+            assert_eq!(bpt.get(&k), Some(&7));
+
+            if k == 5 {
+                assert_eq!(bpt.restructure_counts().0, splits + 1);
+            }
+        }
+        assert!(bpt.restructure_counts().0 >= 2);
+        assert!((0..MAX_KEYS as u64).all(|k| bpt.get(&(k * 10)) == Some(&k)));
+        assert!(bpt.validate());
+    }
+
+    #[test]
+    fn test_bisect_by() {
+        /* values are timestamps that grow with the key */
+        let bpt = BPlusTree::from_unsorted_iter((0..50_u64).map(|k| (k, 1000 + k * k)));
+
+        for threshold in &[0, 1000, 1001, 1200, 1225, 1226, 3401, 3402] {
+            let expected = bpt.entries().find(|&(_, v)| v >= threshold);
+            assert_eq!(bpt.bisect_by(|_, v| v.cmp(threshold)), expected, "{}", threshold);
+        }
+
+        assert_eq!(bpt.bisect_by(|_, v| v.cmp(&1225)), Some((&15, &1225)));
+        assert_eq!(bpt.bisect_by(|_, _| cmp::Ordering::Less), None);
+        assert_eq!(BPlusTree::<u64, u64>::new().bisect_by(|_, _| cmp::Ordering::Equal), None);
+    }
+
+    #[test]
+    fn test_has_entry_between() {
+        let bpt = from_leaves(vec![vec![10, 20], vec![30, 40]]);
+
+        assert!(bpt.has_entry_between(&15, &25));
+        assert!(bpt.has_entry_between(&20, &31));
+        assert!(!bpt.has_entry_between(&20, &30));
+        assert!(!bpt.has_entry_between(&41, &100));
+        assert!(!bpt.has_entry_between(&0, &10));
+        assert!(!bpt.has_entry_between(&30, &20));
+        assert!(!BPlusTree::<u64, u64>::new().has_entry_between(&0, &100));
+    }
+
+    #[test]
+    fn test_sum_keys_in_range() {
+        let bpt = BPlusTree::from_unsorted_iter((0..100_u64).map(|k| (k * 3, ())));
+
+        assert_eq!(bpt.sum_keys_in_range(30..=60), (10..=20).map(|k| k * 3).sum());
+        assert_eq!(bpt.sum_keys_in_range(..), (0..100).map(|k| k * 3).sum());
+        assert_eq!(bpt.sum_keys_in_range(1000..), 0);
+    }
+
+    #[test]
+    fn test_nth_in_range() {
+        let bpt = BPlusTree::from_unsorted_iter((0..100_u64).map(|k| (k, k)));
+
+        assert_eq!(bpt.nth_in_range(10..50, 5), Some((&15, &15)));
+        assert_eq!(bpt.nth_in_range(10..50, 0), Some((&10, &10)));
+        assert_eq!(bpt.nth_in_range(10..50, 39), Some((&49, &49)));
+        assert_eq!(bpt.nth_in_range(10..50, 40), None);
+    }
+
+    #[test]
+    fn test_find_in_range() {
+        let bpt = BPlusTree::from_unsorted_iter((0..30_u64).map(|k| (k, k * 7)));
+
+        assert_eq!(bpt.find_in_range(5..20, |v| v % 2 == 0), Some((&6, &42)));
+        assert_eq!(bpt.find_in_range(5..20, |v| *v > 1000), None);
+        assert_eq!(bpt.find_in_range(..5, |v| *v >= 35), None);
+    }
+
+    #[test]
+    fn test_any_in_range() {
+        let bpt = from_leaves(vec![vec![1, 2, 3], vec![4, 5, 6], vec![7, 8, 9]]);
+        let mut checked = 0;
+
+        assert!(bpt.any_in_range(2..8, |v| {
+            checked += 1;
+            *v == 40
+        }));
+        assert_eq!(checked, 3);
+
+        assert!(!bpt.any_in_range(5.., |v| *v == 40));
+        assert!(!bpt.any_in_range(20.., |_| true));
+    }
+
+    #[test]
+    fn test_for_each_mut() {
+        let mut bpt = from_leaves(vec![vec![1, 2, 3], vec![4, 5], vec![6, 7, 8, 9]]);
+        let mut seen = Vec::new();
+
+        bpt.for_each_mut(|k, v| {
+            seen.push(*k);
+            *v += 1;
+        });
+
+        assert_eq!(seen, (1..10).collect::<Vec<_>>());
+        for k in 1..10 {
+            assert_eq!(bpt.get(&k), Some(&(k * 10 + 1)));
+        }
+
+        BPlusTree::<u64, u64>::new().for_each_mut(|_, _| panic!("nothing to visit"));
+    }
+
+    #[test]
+    fn test_update_sorted() {
+        let mut bpt = from_leaves(vec![vec![1, 2, 3], vec![5, 6], vec![8, 9]]);
+
+        let updates = vec![(0, 0), (2, 21), (3, 31), (4, 41), (6, 61), (9, 91), (12, 0)];
+        assert_eq!(bpt.update_sorted(updates.into_iter()), 4);
+
+        assert_eq!(bpt.entries().map(|(k, v)| (*k, *v)).collect::<Vec<_>>(),
+                   vec![(1, 10), (2, 21), (3, 31), (5, 50), (6, 61), (8, 80), (9, 91)]);
+        assert_eq!(bpt.len(), 7);
+        assert!(bpt.validate());
+    }
+
+    #[test]
+    fn test_into_keys_and_values() {
+        let bpt = from_leaves(vec![vec![1, 2, 3], vec![4, 5], vec![6, 7, 8, 9]]);
+        let expected: Vec<_> = bpt.entries().map(|(k, v)| (*k, *v)).collect();
+
+        assert_eq!(bpt.into_values().collect::<Vec<_>>(), expected.iter().map(|&(_, v)| v).collect::<Vec<_>>());
+
+        let bpt = from_leaves(vec![vec![1, 2, 3], vec![4, 5], vec![6, 7, 8, 9]]);
+        assert_eq!(bpt.into_keys().collect::<Vec<_>>(), (1..10).collect::<Vec<_>>());
+
+        assert_eq!(BPlusTree::<u64, u64>::new().into_keys().count(), 0);
+    }
+
+    #[test]
+    fn test_copy_range_from() {
+        let source = from_leaves(vec![(0..7).collect(), (7..14).collect(), (14..20).collect()]);
+
+        let mut view = BPlusTree::new();
+        view.insert(&5, &0);
+        view.insert(&100, &1000);
+        view.copy_range_from(&source, 5..15);
+
+        let mut expected: Vec<(u64, u64)> = (5..15).map(|k| (k, k * 10)).collect();
+        expected.push((100, 1000));
+        assert_eq!(view.entries().map(|(k, v)| (*k, *v)).collect::<Vec<_>>(), expected);
+        assert_eq!(view.len(), 11);
+        assert!(view.validate());
+    }
+
+    #[test]
+    fn test_range_values() {
+        let bpt = from_leaves(vec![vec![1, 2, 3], vec![4, 5, 6], vec![7, 8, 9]]);
+
+        let pair_sum: u64 = bpt.entries().filter(|&(k, _)| (2..=7).contains(k)).map(|(_, v)| *v).sum();
+        assert_eq!(bpt.range_values(2..=7).sum::<u64>(), pair_sum);
+        assert_eq!(bpt.range_values(..3).collect::<Vec<_>>(), vec![&10, &20]);
+        assert_eq!(bpt.range_values(10..).count(), 0);
+    }
+
+    #[test]
+    fn test_range_into() {
+        let bpt = from_leaves(vec![vec![1, 2, 3], vec![4, 5, 6], vec![7, 8, 9]]);
+        let mut buf = Vec::new();
+
+        for start in 0..11 {
+            for end in start..11 {
+                bpt.range_into(start..end, &mut buf);
+                let expected: Vec<_> = (start..end).filter(|k| (1..10).contains(k)).map(|k| (k, k * 10)).collect();
+                assert_eq!(buf.iter().map(|&(k, v)| (*k, *v)).collect::<Vec<_>>(), expected);
+            }
+        }
+
+        bpt.range_into(.., &mut buf);
+        assert_eq!(buf.len(), 9);
+        bpt.range_into(20.., &mut buf);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_total_key_bytes() {
+        let names: Vec<String> = (0..30).map(|i| format!("customer-{:04}-with-a-fairly-long-name", i)).collect();
+        let bpt = BPlusTree::from_unsorted_iter(names.iter().map(|n| (n.as_str(), ())));
+
+        let mut separators = 0;
+        bpt.visit_preorder(|node| if !node.is_leaf() { separators += node.keys().len() });
+
+        assert!(separators > 0);
+        assert_eq!(bpt.total_key_bytes(), (30 + separators) * names[0].len());
+        assert_eq!(BPlusTree::<&[u8], ()>::new().total_key_bytes(), 0);
+    }
+
+    #[test]
+    fn test_balance_report() {
+        /* 27 leaves under six interior nodes, the last two with 3 and 4 leaves, then (3, 3) under the root */
+        let bpt = BPlusTree::from_unsorted_iter((0..105_u64).map(|k| (k, k)));
+
+        assert_eq!(bpt.balance_report(), BalanceReport {
+            min_leaf_depth: 3,
+            max_leaf_depth: 3,
+            min_fanout: 2,
+            max_fanout: 5,
+            underfull_interiors: 0,
+            is_healthy: true
+        });
+
+        /* whatever the size, bulk loading never leaves a short node below the root */
+        for n in 5..300_u64 {
+            let report = BPlusTree::from_unsorted_iter((0..n).map(|k| (k, k))).balance_report();
+            assert!(report.min_fanout >= 2 && report.is_healthy, "{} entries: {:?}", n, report);
+        }
+
+        let leaf = |k: u64| Rc::new(BPlusNode::Leaf(BPlusLeaf { parent: None, keys: vec![k], values: vec![k] }));
+        let interior = |keys: Vec<u64>, children| Rc::new(BPlusNode::Interior(BPlusInterior { parent: None, keys, children }));
+        let tree = |root| {
+            let mut tree = BPlusTree { root: Some(root), ..BPlusTree::new() };
+            tree.size = tree.entries().count();
+            tree.refresh_key_bounds();
+            tree
+        };
+
+        /* a single child interior node, and the last node on a level gets no pass either */
+        let thin = tree(interior(vec![2], vec![interior(vec![], vec![leaf(1)]), interior(vec![3], vec![leaf(2), leaf(3)])]));
+        let report = thin.balance_report();
+        assert!(thin.validate());
+        assert_eq!(report.underfull_interiors, 2);
+        assert!(!report.is_healthy);
+
+        /* the root alone may run short */
+        let short_root = tree(interior(vec![3, 5], vec![
+            interior(vec![1, 2], vec![leaf(0), leaf(1), leaf(2)]),
+            interior(vec![4], vec![leaf(3), leaf(4)]),
+            interior(vec![6, 7], vec![leaf(5), leaf(6), leaf(7)])
+        ]));
+        let report = short_root.balance_report();
+        assert!(short_root.validate());
+        assert_eq!(report.underfull_interiors, 1);
+
+        let healthy = tree(interior(vec![3], vec![
+            interior(vec![1, 2], vec![leaf(0), leaf(1), leaf(2)]),
+            interior(vec![4, 5], vec![leaf(3), leaf(4), leaf(5)])
+        ]));
+        assert!(healthy.balance_report().is_healthy);
+
+        /* leaves at two different depths */
+        let skewed = tree(interior(vec![2], vec![leaf(1), interior(vec![3], vec![leaf(2), leaf(3)])]));
+        let report = skewed.balance_report();
+        assert_eq!((report.min_leaf_depth, report.max_leaf_depth), (1, 2));
+        assert!(!report.is_healthy);
+
+        assert_eq!(BPlusTree::<u64, u64>::new().balance_report().min_fanout, 0);
+    }
+
+    #[test]
+    fn test_detect_misplaced_keys() {
+        let mut bpt = from_leaves(vec![vec![1, 2, 3], vec![4, 5, 6], vec![7, 8, 9]]);
+        assert!(bpt.detect_misplaced_keys().is_empty());
+
+        /* slip 8 into the first leaf, as a key whose ordering changed might be */
+        bpt.drain_range(8..=8).count();
+        if let BPlusNode::Leaf(ref mut leaf) = *Rc::get_mut(&mut bpt.interior_at(&[]).children[0]).unwrap() {
+            leaf.keys.push(8);
+            leaf.values.push(80);
+        }
+
+        assert_eq!(bpt.detect_misplaced_keys(), vec![8]);
+        assert_eq!(bpt.get(&8), None);
+    }
+
+    #[test]
+    fn test_swap_children() {
+        let keys = |bpt: &BPlusTree<u64, u64>| bpt.entries().map(|(k, _)| *k).collect::<Vec<_>>();
+
+        /* swapping two non-empty leaves would put 5 before 1 */
+        let mut bpt = from_leaves(vec![vec![1, 2], vec![3], vec![5, 6]]);
+        assert!(!bpt.swap_children(&[], 0, 2));
+        assert_eq!(keys(&bpt), vec![1, 2, 3, 5, 6]);
+        assert!(bpt.validate());
+
+        /* an emptied leaf can move to the front */
+        bpt.remove_entry(&3);
+        assert!(bpt.swap_children(&[], 0, 1));
+        assert!(bpt.validate());
+        assert_eq!(keys(&bpt), vec![1, 2, 5, 6]);
+
+        let mut leaves = Vec::new();
+        bpt.visit_preorder(|node| if node.is_leaf() { leaves.push(node.keys().to_vec()) });
+        assert_eq!(leaves, vec![vec![], vec![1, 2], vec![5, 6]]);
+    }
+
+    #[test]
+    fn test_retain_range() {
+        let mut bpt = BPlusTree::from_unsorted_iter((0..40_u64).map(|k| (k, k)));
+
+        assert_eq!(bpt.retain_range(10..=20), 29);
+        assert_eq!(bpt.entries().map(|(k, _)| *k).collect::<Vec<_>>(), (10..=20).collect::<Vec<_>>());
+        assert!(bpt.validate());
+
+        assert_eq!(bpt.retain_range(..), 0);
+        assert_eq!(bpt.retain_range(15..), 5);
+        assert_eq!(bpt.retain_range(100..), 6);
+        assert!(bpt.is_empty());
+        assert!(bpt.validate());
+
+        /* cuts at every offset of a deeper tree, including ones between keys */
+        for lo in (0..420).step_by(7) {
+            for &len in &[0, 1, 5, 33, 150] {
+                let mut bpt = BPlusTree::from_unsorted_iter((0..200_u64).map(|k| (k * 2, k)));
+                let kept = (0..200).filter(|&k| lo <= k * 2 && k * 2 < lo + len).count();
+
+                assert_eq!(bpt.retain_range(lo..lo + len), 200 - kept);
+                assert_eq!(bpt.len(), kept);
+                assert!(bpt.entries().all(|(k, _)| lo <= *k && *k < lo + len));
+                assert!(bpt.leaves().iter().all(|leaf| !leaf.keys.is_empty()));
+                assert!(bpt.validate());
+
+                bpt.insert(&1000, &0);
+                assert!(bpt.validate());
+            }
+        }
+    }
+
+    #[test]
+    fn test_clone_path_to_leaf() {
+        /* collects every node as a pointer, parents before children */
+        fn nodes(node: &Rc<BPlusNode<u64, u64>>, out: &mut Vec<*const BPlusNode<u64, u64>>) {
+            out.push(&**node);
+
+            if let BPlusNode::Interior(ref interior) = **node {
+                for child in &interior.children {
+                    nodes(child, out);
+                }
+            }
+        }
+
+        let mut bpt = BPlusTree::from_unsorted_iter((0..40_u64).map(|k| (k, k)));
+        let snapshot = bpt.root.clone().unwrap();
+
+        {
+            let leaf = bpt.clone_path_to_leaf(&17).unwrap();
+            let i = leaf.keys.binary_search(&17).unwrap();
+            leaf.values[i] = 1700;
+        }
+
+        let (mut before, mut after) = (Vec::new(), Vec::new());
+        nodes(&snapshot, &mut before);
+        nodes(bpt.root.as_ref().unwrap(), &mut after);
+
+        /* root, one interior node and one leaf were copied, nothing else */
+        assert_eq!(before.len(), after.len());
+        assert_eq!(before.iter().zip(&after).filter(|&(a, b)| a != b).count(), 3);
+
+        assert_eq!(bpt.get(&17), Some(&1700));
+        assert!(bpt.validate());
+
+        let old = BPlusTree { root: Some(snapshot), ..BPlusTree::new() };
+        assert_eq!(old.get(&17), Some(&17));
+    }
+
+    #[test]
+    fn test_estimate_serialized_size() {
+        let mut bpt = BPlusTree::<u32, u64>::new();
+
+        for k in 0..100 {
+            bpt.insert(&k, &(k as u64 * 1000));
+        }
+
+        let mut bytes = Vec::new();
+        for k in 10..20_u32 {
+            bytes.extend_from_slice(&k.to_le_bytes());
+            bytes.extend_from_slice(&(k as u64 * 1000).to_le_bytes());
+        }
+
+        assert_eq!(bpt.estimate_serialized_size(10..20), bytes.len());
+        assert_eq!(bpt.estimate_serialized_size(200..), 0);
+
+        /* a varint style encoding where small values only take one byte */
+        let varint = |_: &u32, v: &u64| 4 + if *v < 128 { 1 } else { 3 };
+        assert_eq!(bpt.estimate_serialized_size_with(..=1, varint), 12);
+    }
+
+    #[test]
+    fn test_from_sorted_sources() {
+        let sources: Vec<Box<dyn Iterator<Item = (u64, char)>>> = vec![
+            Box::new(vec![(1, 'a'), (4, 'a'), (7, 'a')].into_iter()),
+            Box::new(vec![(2, 'b'), (4, 'b'), (8, 'b')].into_iter()),
+            Box::new(vec![(4, 'c'), (7, 'c'), (9, 'c')].into_iter()),
+        ];
+
+        let bpt = BPlusTree::from_sorted_sources(sources);
+        let entries: Vec<_> = bpt.entries().map(|(k, v)| (*k, *v)).collect();
+
+        assert_eq!(entries, vec![(1, 'a'), (2, 'b'), (4, 'c'), (7, 'c'), (8, 'b'), (9, 'c')]);
+        assert_eq!(bpt.len(), 6);
+        assert!(bpt.validate());
+
+        /* big enough to need several levels */
+        let sources: Vec<Box<dyn Iterator<Item = (u64, u64)>>> = vec![
+            Box::new((0..300).filter(|k| k % 3 == 0).map(|k| (k, 0))),
+            Box::new((0..300).filter(|k| k % 2 == 0).map(|k| (k, 1))),
+        ];
+
+        let bpt = BPlusTree::from_sorted_sources(sources);
+        assert_eq!(bpt.len(), 200);
+        assert!(bpt.validate());
+        assert_eq!(bpt.get(&6), Some(&1));
+        assert_eq!(bpt.get(&9), Some(&0));
+    }
+
+    #[test]
+    fn test_range_endpoints() {
+        let bpt = from_leaves(vec![vec![1, 3, 5], vec![7, 9, 11], vec![13, 15, 17]]);
+
+        assert_eq!(bpt.range_endpoints(4..14), Some(((&5, &50), (&13, &130))));
+        assert_eq!(bpt.range_endpoints(6..=11), Some(((&7, &70), (&11, &110))));
+        assert_eq!(bpt.range_endpoints(..), Some(((&1, &10), (&17, &170))));
+        assert_eq!(bpt.range_endpoints(12..14), Some(((&13, &130), (&13, &130))));
+        assert_eq!(bpt.range_endpoints(12..13), None);
+        assert_eq!(bpt.range_endpoints(18..), None);
+        assert_eq!(BPlusTree::<u64, u64>::new().range_endpoints(..), None);
+    }
+
+    #[test]
+    fn test_get_nearest() {
+        let bpt = from_leaves(vec![vec![10, 20, 24], vec![30, 45], vec![46, 60, 100]]);
+        let keys: Vec<u64> = bpt.entries().map(|(k, _)| *k).collect();
+
+        for probe in 0..120 {
+            let expected = keys.iter().min_by_key(|k| (k.distance(&probe), **k)).unwrap();
+            assert_eq!(bpt.get_nearest(&probe).map(|(k, _)| k), Some(expected));
+        }
+
+        /* ties go to the lower key */
+        assert_eq!(bpt.get_nearest(&22), Some((&20, &200)));
+        assert_eq!(bpt.get_nearest(&30), Some((&30, &300)));
+        assert_eq!(BPlusTree::<u64, u64>::new().get_nearest(&5), None);
+
+        let mut signed = BPlusTree::<i8, ()>::new();
+        signed.insert(&i8::MIN, &());
+        signed.insert(&i8::MAX, &());
+        assert_eq!(signed.get_nearest(&-1).map(|(k, _)| *k), Some(i8::MIN));
+        assert_eq!(signed.get_nearest(&0).map(|(k, _)| *k), Some(i8::MAX));
+    }
+
+    #[test]
+    fn test_insert_unique_batch() {
+        let mut bpt = BPlusTree::<u64, u64>::new();
+        bpt.insert(&5, &50);
+
+        assert_eq!(bpt.insert_unique_batch(vec![(1, 10), (2, 20), (1, 11)]), Err(BPlusError::DuplicateKey(1)));
+        assert_eq!(bpt.insert_unique_batch(vec![(3, 30), (5, 51)]), Err(BPlusError::DuplicateKey(5)));
+        assert_eq!(bpt.entries().count(), 1);
+
+        assert_eq!(bpt.insert_unique_batch(vec![(3, 30), (1, 10)]), Ok(()));
+        assert_eq!(bpt.entries().count(), 3);
+    }
+
+    #[test]
+    fn test_get_many() {
+        let bpt = from_leaves(vec![vec![2, 4, 6], vec![8, 10, 12], vec![14, 16, 18]]);
+
+        /* a simple LCG so the probes are scattered but repeatable */
+        let mut x = 7_u64;
+        let random: Vec<u64> = (0..50).map(|_| {
+            x = x.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (x >> 33) % 22
+        }).collect();
+        let clustered: Vec<u64> = vec![9, 8, 10, 11, 12, 12, 3, 2, 4, 19, 18];
+
+        for probes in [random, clustered.clone()].iter() {
+            let expected: Vec<_> = probes.iter().map(|k| bpt.get(k)).collect();
+            assert_eq!(bpt.get_many(probes), expected);
+        }
+
+        /* probes that share a leaf only descend once between them */
+        bpt.descents.set(0);
+        for k in clustered.iter() {
+            bpt.get(k);
+        }
+        assert_eq!(bpt.descents.get(), clustered.len());
+
+        bpt.descents.set(0);
+        bpt.get_many(&clustered);
+        assert_eq!(bpt.descents.get(), 3);
+
+        assert_eq!(bpt.get_many(&[]), vec![]);
+        assert_eq!(BPlusTree::<u64, u64>::new().get_many(&[1, 2]), vec![None, None]);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut bpt = BPlusTree::<u64, u64>::new();
+
+        for k in (0..100).rev() {
+            bpt.insert(&k, &k);
+        }
+
+        bpt.truncate(40);
+        assert_eq!(bpt.entries().map(|(k, _)| *k).collect::<Vec<_>>(), (0..40).collect::<Vec<_>>());
+        assert!(bpt.balance_report().is_healthy);
+        assert!(bpt.validate());
+
+        /* cutting a deep tree down to one entry collapses it to a single leaf */
+        let mut bpt = BPlusTree::from_unsorted_iter((0..200_u64).map(|k| (k, k)));
+        bpt.truncate(1);
+        assert_eq!(bpt.entries().collect::<Vec<_>>(), vec![(&0, &0)]);
+        assert!(matches!(**bpt.root.as_ref().unwrap(), BPlusNode::Leaf(_)));
+        assert!(bpt.balance_report().is_healthy);
+        assert!(bpt.validate());
+
+        let mut bpt = from_leaves(vec![vec![1, 2, 3], vec![4, 5, 6], vec![7, 8, 9]]);
+        bpt.truncate(5);
+        assert_eq!(bpt.entries().map(|(k, _)| *k).collect::<Vec<_>>(), vec![1, 2, 3, 4, 5]);
+        assert_eq!(bpt.get(&6), None);
+        assert_eq!(bpt.get(&5), Some(&50));
+
+        bpt.truncate(10);
+        assert_eq!(bpt.entries().count(), 5);
+
+        bpt.truncate(0);
+        assert_eq!(bpt.entries().count(), 0);
+
+        /* every cut point leaves a balanced tree that still takes inserts */
+        for len in 1..300 {
+            let mut bpt = BPlusTree::from_unsorted_iter((0..300_u64).map(|k| (k, k)));
+            bpt.truncate(len);
+            assert_eq!(bpt.len(), len);
+            assert!(bpt.balance_report().is_healthy, "{}: {:?}", len, bpt.balance_report());
+            assert!(bpt.validate());
+
+            bpt.insert(&1000, &0);
+            assert!(bpt.validate());
+        }
+    }
+
+    #[test]
+    fn test_key_exists_fast() {
+        let mut bpt = from_leaves(vec![vec![10, 20], vec![30, 40]]);
+
+        assert!(!bpt.key_exists_fast(&5));
+        assert!(!bpt.key_exists_fast(&41));
+        assert_eq!(bpt.descents.get(), 0);
+
+        assert!(bpt.key_exists_fast(&30));
+        assert!(!bpt.key_exists_fast(&25));
+        assert_eq!(bpt.descents.get(), 2);
+
+        bpt.truncate(3);
+        assert!(!bpt.key_exists_fast(&40));
+        assert!(bpt.key_exists_fast(&30));
+
+        let mut bpt = BPlusTree::<u64, u64>::new();
+        assert!(!bpt.key_exists_fast(&1));
+        bpt.insert(&1, &1);
+        bpt.insert(&9, &9);
+        assert!(bpt.key_exists_fast(&1) && bpt.key_exists_fast(&9));
+    }
+
+    #[test]
+    fn test_approx_median_key() {
+        for &n in &[1_u64, 7, 40, 333, 1000] {
+            let bpt = BPlusTree::from_unsorted_iter((0..n).map(|k| (k * 2, k)));
+            let median = *bpt.approx_median_key().unwrap() / 2;
+
+            /* within a fifth of the tree of the real middle */
+            assert!((median as i64 - (n / 2) as i64).abs() <= (n / 5 + 1) as i64, "{} of {}", median, n);
+        }
+
+        assert_eq!(BPlusTree::<u64, u64>::new().approx_median_key(), None);
+
+        /* landing on an emptied leaf moves on to the next key, or back to the last one */
+        let mut bpt = from_leaves(vec![vec![1, 2], vec![3, 4], vec![5, 6]]);
+        assert_eq!(bpt.approx_median_key(), Some(&4));
+
+        bpt.remove_entry(&3);
+        bpt.remove_entry(&4);
+        assert_eq!(bpt.approx_median_key(), Some(&5));
+
+        bpt.remove_entry(&5);
+        bpt.remove_entry(&6);
+        assert_eq!(bpt.approx_median_key(), Some(&2));
+    }
+
+    #[test]
+    fn test_expected_height() {
+        let height = BPlusTree::<u64, u64>::expected_height;
+
+        assert_eq!(height(5, 0), 0);
+        assert_eq!(height(5, 4), 1);
+        assert_eq!(height(5, 5), 2);
+        assert_eq!(height(5, 20), 2);
+        assert_eq!(height(5, 21), 3);
+        assert_eq!(height(101, 1_000_000), 3);
+        assert_eq!(height(2, 8), 4);
+
+        for &n in &[1_u64, 4, 5, 20, 21, 100, 101, 500] {
+            let bpt = BPlusTree::from_unsorted_iter((0..n).map(|k| (k, k)));
+            assert_eq!(BPlusTree::height(bpt.root.as_ref().unwrap()), height(5, n as usize), "{}", n);
+        }
+    }
+
+    #[test]
+    fn test_lca_depth() {
+        /* ten leaves under two interior nodes under the root */
+        let bpt = BPlusTree::from_unsorted_iter((0..40_u64).map(|k| (k, k)));
+
+        assert_eq!(bpt.lca_depth(&0, &3), Some(1));
+        assert_eq!(bpt.lca_depth(&0, &5), Some(1));
+        assert_eq!(bpt.lca_depth(&0, &39), Some(0));
+        assert_eq!(bpt.lca_depth(&39, &0), Some(0));
+
+        let small = from_leaves(vec![vec![1, 2], vec![3, 4]]);
+        assert_eq!(small.lca_depth(&1, &2), Some(0));
+        assert_eq!(small.lca_depth(&1, &4), Some(0));
+
+        let mut leaf = BPlusTree::new();
+        leaf.insert(&1, &1);
+        assert_eq!(leaf.lca_depth(&1, &9), Some(0));
+        assert_eq!(BPlusTree::<u64, u64>::new().lca_depth(&1, &2), None);
+    }
+
+    #[test]
+    fn test_visit_order() {
+        let bpt = from_leaves(vec![vec![1, 2], vec![3, 4], vec![5, 6]]);
+
+        let mut leaves = 0;
+        bpt.visit_preorder(|node| if node.is_leaf() { leaves += 1 });
+        assert_eq!(leaves, bpt.leaves().len());
+
+        let mut pre = Vec::new();
+        bpt.visit_preorder(|node| pre.push(node.keys().to_vec()));
+        assert_eq!(pre, vec![vec![3, 5], vec![1, 2], vec![3, 4], vec![5, 6]]);
+
+        let mut post = Vec::new();
+        bpt.visit_postorder(|node| post.push((node.keys().to_vec(), node.children().len())));
+        assert_eq!(post.last(), Some(&(vec![3, 5], 3)));
+        assert_eq!(post[0], (vec![1, 2], 0));
+
+        let mut values = Vec::new();
+        bpt.visit_postorder(|node| if let Some(v) = node.values() { values.extend_from_slice(v) });
+        assert_eq!(values, vec![10, 20, 30, 40, 50, 60]);
+    }
+
+    #[test]
+    fn test_count_distinct_values_in_range() {
+        let mut bpt = BPlusTree::<u64, u64>::new();
+
+        for k in 0..20 {
+            bpt.insert(&k, &(k % 3));
+        }
+
+        assert_eq!(bpt.count_distinct_values_in_range(..), 3);
+        assert_eq!(bpt.count_distinct_values_in_range(3..5), 2);
+        assert_eq!(bpt.count_distinct_values_in_range(6..=6), 1);
+        assert_eq!(bpt.count_distinct_values_in_range(50..), 0);
+    }
+
+    #[test]
+    fn test_count_values() {
+        let bpt = from_leaves(vec![vec![1, 2, 3], vec![4, 5, 6, 7], vec![8, 9]]);
+
+        let manual = bpt.entries().filter(|&(_, v)| *v > 45).count();
+        assert_eq!(bpt.count_values(|v| *v > 45), manual);
+        assert_eq!(manual, 5);
+
+        assert_eq!(bpt.count_values(|_| true), bpt.len());
+        assert_eq!(BPlusTree::<u64, u64>::new().count_values(|_| true), 0);
+    }
+
+    #[test]
+    fn test_from_iter_first_wins() {
+        let bpt = BPlusTree::from_iter_first_wins(vec![(1, 'a'), (1, 'b'), (2, 'c')].into_iter());
+        assert_eq!(bpt.get(&1), Some(&'a'));
+        assert_eq!(bpt.len(), 2);
+
+        let pairs = vec![(3, 'a'), (1, 'b'), (3, 'c'), (2, 'd'), (1, 'e'), (3, 'f')];
+        let bpt = BPlusTree::from_iter_first_wins(pairs.into_iter());
+        assert_eq!(bpt.entries().map(|(k, v)| (*k, *v)).collect::<Vec<_>>(), vec![(1, 'b'), (2, 'd'), (3, 'a')]);
+        assert!(bpt.validate());
+    }
+
+    #[test]
+    fn test_from_unsorted_vec() {
+        /* only 13 distinct keys, each repeated many times */
+        let pairs: Vec<(u64, u64)> = (0..500).map(|i| ((i * 7) % 13, i)).collect();
+
+        let mut inserted = BPlusTree::new();
+        for &(k, v) in &pairs {
+            inserted.insert(&k, &v);
+        }
+
+        let loaded = BPlusTree::from_unsorted_vec(pairs.clone());
+        assert_eq!(loaded.entries().collect::<Vec<_>>(), inserted.entries().collect::<Vec<_>>());
+        assert_eq!(loaded.len(), 13);
+        assert!(loaded.validate());
+
+        let loaded = BPlusTree::from_unsorted_iter(pairs.into_iter().rev());
+        assert_eq!(loaded.get(&0), Some(&0));
+        assert!(loaded.validate());
+    }
+
+    #[test]
+    fn test_merge_many() {
+        use std::collections::BTreeMap;
+
+        /* the value records which input it came from */
+        let inputs: Vec<Vec<u64>> = vec![vec![1, 3, 5, 7], vec![2, 3, 9], vec![], vec![3, 7, 10, 11, 12, 13]];
+        let trees = inputs.iter().enumerate()
+            .map(|(i, keys)| BPlusTree::from_unsorted_iter(keys.iter().map(|&k| (k, i as u64))))
+            .collect();
+
+        let mut calls = Vec::new();
+        let merged = BPlusTree::merge_many(trees, |k, vs| {
+            calls.push((*k, vs.clone()));
+            vs.iter().sum()
+        });
+
+        assert_eq!(calls, vec![(3, vec![0, 1, 3]), (7, vec![0, 3])]);
+
+        let mut reference = BTreeMap::new();
+        for (i, keys) in inputs.iter().enumerate() {
+            for &k in keys {
+                *reference.entry(k).or_insert(0) += i as u64;
+            }
+        }
+
+        assert_eq!(merged.entries().map(|(k, v)| (*k, *v)).collect::<Vec<_>>(), reference.into_iter().collect::<Vec<_>>());
+        assert!(merged.validate());
+
+        let disjoint = vec![BPlusTree::from_unsorted_iter(vec![(5, 0)]), BPlusTree::from_unsorted_iter(vec![(1, 1)])];
+        let merged = BPlusTree::merge_many(disjoint, |_, _| panic!("no key repeats"));
+        assert_eq!(merged.entries().map(|(k, v)| (*k, *v)).collect::<Vec<_>>(), vec![(1, 1), (5, 0)]);
+        assert!(BPlusTree::<u64, u64>::merge_many(Vec::new(), |_, _| 0).is_empty());
+    }
+
+    #[test]
+    fn test_from_iter_last_wins() {
+        let pairs = vec![(1, 'a'), (1, 'b'), (2, 'c'), (0, 'd'), (2, 'e'), (1, 'f')];
+        let bpt = BPlusTree::from_iter_last_wins(pairs.into_iter());
+
+        assert_eq!(bpt.entries().map(|(k, v)| (*k, *v)).collect::<Vec<_>>(), vec![(0, 'd'), (1, 'f'), (2, 'e')]);
+
+        let bpt = BPlusTree::from_iter_last_wins(vec![(1, 'a'), (1, 'b'), (2, 'c')].into_iter());
+        assert_eq!(bpt.get(&1), Some(&'b'));
+
+        /* the bulk loaded tree keeps taking inserts */
+        let mut bpt = BPlusTree::from_iter_last_wins((0..50_u64).rev().map(|k| (k * 2, 'x')));
+        for k in 0..100 {
+            bpt.insert(&k, &'y');
+        }
+        assert_eq!(bpt.len(), 100);
+        assert!(bpt.entries().all(|(_, v)| *v == 'y'));
+        assert!(bpt.validate());
+    }
+
+    #[test]
+    fn test_page_after() {
+        let mut bpt = BPlusTree::<u64, u64>::new();
+
+        for k in 0..50 {
+            bpt.insert(&(k * 2), &k);
+        }
+
+        let mut seen = Vec::new();
+        let mut behind = Vec::new();
+        let mut ahead = Vec::new();
+        let mut token = None;
+
+        loop {
+            let (page, next) = bpt.page_after(token.as_ref(), 7);
+            assert!(page.len() <= 7);
+            seen.extend(page.iter().map(|&(k, _)| *k));
+
+            let next = match next {
+                Some(next) => next,
+                None => break
+            };
+
+            /* send the token out as bytes and back, as a client would */
+            let bytes = next.to_bytes();
+            token = Some(PageToken::from_bytes(&bytes).unwrap());
+            assert_eq!(token, Some(next));
+
+            /* mutate between pages: one key behind the token, one ahead of it */
+            let last = *seen.last().unwrap();
+            behind.push(last - 1);
+            ahead.push(last + 1);
+            bpt.insert(&(last - 1), &0);
+            bpt.insert(&(last + 1), &0);
+        }
+
+        /* every page picks up strictly after the last key it saw */
+        assert!(seen.windows(2).all(|w| w[0] < w[1]));
+        assert!((0..50).all(|k| seen.contains(&(k * 2))));
+        assert!(ahead.iter().all(|k| seen.contains(k)));
+        assert!(behind.iter().all(|k| !seen.contains(k)));
+
+        assert_eq!(bpt.page_after(None, 0), (vec![], None));
+        assert_eq!(PageToken::<u64>::from_bytes(&[1, 2]), Err(DecodeError::UnexpectedEnd));
+    }
+
+    #[test]
+    fn test_page() {
+        let bpt = from_leaves(vec![vec![1, 2, 3], vec![4, 5, 6, 7], vec![8, 9]]);
+
+        let mut seen = Vec::new();
+        let mut after = None;
+
+        loop {
+            let (page, next) = bpt.page(after, 2);
+            seen.extend(page.iter().map(|&(k, _)| *k));
+
+            if next.is_none() {
+                break;
+            }
+            after = next;
+        }
+
+        assert_eq!(seen, (1..10).collect::<Vec<_>>());
+        assert_eq!(bpt.page(Some(&3), 3).0, vec![(&4, &40), (&5, &50), (&6, &60)]);
+        assert_eq!(bpt.page(Some(&3), 3).1, Some(&6));
+        assert_eq!(bpt.page(Some(&9), 3), (vec![], None));
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let mut bpt = BPlusTree::<u64, u64>::new();
+        for k in 1..5 {
+            bpt.insert(&k, &(k * 10));
+        }
+
+        /* absent -> present */
+        assert_eq!(bpt.compare_and_swap(&5, None, Some(50)), Ok(()));
+        assert_eq!(bpt.get(&5), Some(&50));
+
+        /* present -> present */
+        assert_eq!(bpt.compare_and_swap(&3, Some(&30), Some(31)), Ok(()));
+        assert_eq!(bpt.get(&3), Some(&31));
+
+        /* present -> absent */
+        assert_eq!(bpt.compare_and_swap(&1, Some(&10), None), Ok(()));
+        assert_eq!(bpt.get(&1), None);
+        assert!(!bpt.key_exists_fast(&1));
+
+        /* absent -> absent */
+        assert_eq!(bpt.compare_and_swap(&7, None, None), Ok(()));
+        assert_eq!(bpt.get(&7), None);
+
+        /* failures hand back what is there and what was refused */
+        assert_eq!(bpt.compare_and_swap(&3, Some(&30), Some(32)), Err(CasError { current: Some(31), rejected: Some(32) }));
+        assert_eq!(bpt.compare_and_swap(&2, None, Some(0)), Err(CasError { current: Some(20), rejected: Some(0) }));
+        assert_eq!(bpt.compare_and_swap(&9, Some(&90), None), Err(CasError { current: None, rejected: None }));
+        assert_eq!(bpt.get(&3), Some(&31));
+
+        /* the increment loop a caller would write */
+        for _ in 0..10 {
+            loop {
+                let current = *bpt.get(&4).unwrap();
+                if bpt.compare_and_swap(&4, Some(&current), Some(current + 1)).is_ok() {
+                    break;
+                }
+            }
+        }
+        assert_eq!(bpt.get(&4), Some(&50));
+    }
+
+    #[test]
+    fn test_replace_if() {
+        let mut bpt = from_leaves(vec![vec![1, 2], vec![3, 4]]);
+
+        assert_eq!(bpt.replace_if(&3, 31, |v| *v == 30), Ok(Some(30)));
+        assert_eq!(bpt.get(&3), Some(&31));
+
+        assert_eq!(bpt.replace_if(&3, 32, |v| *v == 30), Err(32));
+        assert_eq!(bpt.get(&3), Some(&31));
+
+        assert_eq!(bpt.replace_if(&5, 50, |_| true), Err(50));
+        assert_eq!(bpt.get(&5), None);
+    }
+
+    #[test]
+    fn test_compact_into() {
+        let bpt = from_leaves(vec![vec![1], vec![2, 3], vec![4], vec![5, 6], vec![7]]);
+        let compact = bpt.compact_into();
+
+        assert_eq!(compact.entries().collect::<Vec<_>>(), bpt.entries().collect::<Vec<_>>());
+        assert_eq!(bpt.leaves().len(), 5);
+        assert_eq!(compact.leaves().len(), 2);
+        assert!(compact.utilization() > bpt.utilization());
+
+        /* enough entries for a couple of interior levels */
+        let big = BPlusTree::from_sorted_vec((0..100_u64).map(|k| (k, k)).collect());
+        assert_eq!(big.entries().count(), 100);
+        assert!((0..100).all(|k| big.get(&k) == Some(&k)));
+        assert_eq!(big.compact_into().utilization(), 1.0);
+
+        assert_eq!(BPlusTree::<u64, u64>::new().compact_into().entries().count(), 0);
+    }
+
+    #[test]
+    fn test_apply_diff() {
+        let build = |pairs: &[(u64, u64)]| {
+            let mut bpt = BPlusTree::<u64, u64>::new();
+            for &(k, v) in pairs {
+                bpt.insert(&k, &v);
+            }
+            bpt
+        };
+
+        let a = [(1, 10), (2, 20), (3, 30), (5, 50)];
+        let b = [(2, 20), (3, 33), (4, 40), (5, 50), (6, 60)];
+        let (old, new) = (build(&a), build(&b));
+
+        /* walk both trees in key order, like a diff would */
+        let mut diff = Vec::new();
+        let mut olds = old.entries().peekable();
+        let mut news = new.entries().peekable();
+
+        loop {
+            match (olds.peek().cloned(), news.peek().cloned()) {
+                (Some((ok, ov)), Some((nk, nv))) if ok == nk => {
+                    if ov != nv {
+                        diff.push(Diff::Changed(*ok, *ov, *nv));
+                    }
+                    olds.next();
+                    news.next();
+                },
+                (Some((ok, ov)), Some((nk, _))) if ok < nk => {
+                    diff.push(Diff::Removed(*ok, *ov));
+                    olds.next();
+                },
+                (Some((ok, ov)), None) => {
+                    diff.push(Diff::Removed(*ok, *ov));
+                    olds.next();
+                },
+                (_, Some((nk, nv))) => {
+                    diff.push(Diff::Added(*nk, *nv));
+                    news.next();
+                },
+                (None, None) => break
+            }
+        }
+
+        assert_eq!(diff, vec![Diff::Removed(1, 10), Diff::Changed(3, 30, 33), Diff::Added(4, 40), Diff::Added(6, 60)]);
+
+        let mut replica = build(&a);
+        replica.apply_diff(diff.into_iter());
+        assert_eq!(replica.entries().collect::<Vec<_>>(), new.entries().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_visit() {
+        struct Collector {
+            entries: Vec<(u64, u64)>,
+            leaf_depths: Vec<usize>,
+            open: Vec<usize>
+        }
+
+        impl TreeVisitor<u64, u64> for Collector {
+            fn enter_interior(&mut self, depth: usize, keys: &[u64], child_count: usize) {
+                assert_eq!(keys.len() + 1, child_count);
+                assert_eq!(depth, self.open.len());
+                self.open.push(depth);
+            }
+
+            fn leaf(&mut self, depth: usize, keys: &[u64], values: &[u64]) {
+                assert_eq!(depth, self.open.len());
+                self.leaf_depths.push(depth);
+                self.entries.extend(keys.iter().cloned().zip(values.iter().cloned()));
+            }
+
+            fn exit_interior(&mut self, depth: usize) {
+                assert_eq!(self.open.pop(), Some(depth));
+            }
+        }
+
+        let bpt = BPlusTree::from_sorted_vec((0..60_u64).map(|k| (k, k * 3)).collect());
+        let mut collector = Collector { entries: Vec::new(), leaf_depths: Vec::new(), open: Vec::new() };
+        bpt.visit(&mut collector);
+
+        assert_eq!(collector.entries, bpt.entries().map(|(k, v)| (*k, *v)).collect::<Vec<_>>());
+        assert!(collector.open.is_empty());
+
+        /* 15 leaves under a fanout of 5 is three levels, so leaves sit at depth 2 */
+        assert_eq!(collector.leaf_depths.len(), 15);
+        assert!(collector.leaf_depths.iter().all(|&d| d == 2));
+    }
+
+    #[test]
+    fn test_range_min_max() {
+        let mut bpt = BPlusTree::<u64, i64>::new();
+
+        for (k, v) in [(1, 5), (2, -3), (3, 9), (4, 0), (5, 12), (6, 7)].iter() {
+            bpt.insert(k, v);
+        }
+
+        assert_eq!(bpt.range_min_max(..), Some((&-3, &12)));
+        assert_eq!(bpt.range_min_max(3..5), Some((&0, &9)));
+        assert_eq!(bpt.range_min_max(6..), Some((&7, &7)));
+        assert_eq!(bpt.range_min_max(7..), None);
+    }
+
+    #[test]
+    fn test_clear_retaining() {
+        let pairs: Vec<(u64, u64)> = (0..24).map(|k| (k, k)).collect();
+        let mut bpt = BPlusTree::from_sorted_vec(pairs.clone());
+        let before: Vec<*const u64> = bpt.leaves().iter().map(|leaf| leaf.keys.as_ptr()).collect();
+
+        bpt.clear_retaining();
+        assert!(bpt.is_empty());
+        assert_eq!(bpt.get(&3), None);
+        assert!(bpt.validate());
+
+        /* the same amount of data again fits in exactly the old leaves */
+        bpt.reload(pairs.iter().map(|&(k, v)| (k, v + 1)).collect());
+        assert_eq!(bpt.len(), 24);
+        assert_eq!(bpt.get(&3), Some(&4));
+        assert!(bpt.validate());
+
+        let after: Vec<*const u64> = bpt.leaves().iter().map(|leaf| leaf.keys.as_ptr()).collect();
+        assert_eq!(after.len(), before.len());
+        assert!(after.iter().all(|p| before.contains(p)));
+    }
+
+    #[test]
+    fn test_compact_reuses_leaves() {
+        /* pack 24 entries into six full leaves, then thin them out */
+        let mut bpt = BPlusTree::from_sorted_vec((0..24_u64).map(|k| (k, k)).collect());
+        for k in (0..24).filter(|k| k % 3 != 0) {
+            bpt.remove_entry(&k);
+        }
+        let before: Vec<*const u64> = bpt.leaves().iter().map(|leaf| leaf.keys.as_ptr()).collect();
+        assert_eq!(before.len(), 6);
+
+        bpt.compact();
+
+        assert_eq!(bpt.entries().map(|(k, _)| *k).collect::<Vec<_>>(), (0..8).map(|k| k * 3).collect::<Vec<_>>());
+        assert_eq!(bpt.leaves().len(), 2);
+        assert!(bpt.leaves().iter().all(|leaf| before.contains(&leaf.keys.as_ptr())));
+        assert_eq!(bpt.utilization(), 1.0);
+
+        /* half full leaves left behind by splitting pack back down */
+        let mut bpt = BPlusTree::<u64, u64>::new();
+        for k in 0..10 {
+            bpt.insert(&k, &k);
+        }
+        bpt.compact();
+        assert_eq!(bpt.leaves().len(), 3);
+        assert!((0..10).all(|k| bpt.get(&k) == Some(&k)));
+
+        /* and the packed tree takes inserts again, full leaves and all */
+        for k in 0..30 {
+            bpt.insert(&(10 + k * 7 % 30), &0);
+        }
+        assert_eq!(bpt.len(), 40);
+        assert!(bpt.validate());
+    }
+
+    #[test]
+    fn test_page_desc() {
+        let bpt = from_leaves(vec![vec![1, 2, 3], vec![4, 5, 6, 7], vec![8, 9]]);
+
+        let mut seen = Vec::new();
+        let mut before = None;
+
+        loop {
+            let (page, next) = bpt.page_desc(before, 4);
+            seen.extend(page.iter().map(|&(k, _)| *k));
+
+            if next.is_none() {
+                break;
+            }
+            before = next;
+        }
+
+        assert_eq!(seen, (1..10).rev().collect::<Vec<_>>());
+        assert_eq!(bpt.page_desc(Some(&5), 2), (vec![(&4, &40), (&3, &30)], Some(&3)));
+        assert_eq!(bpt.page_desc(Some(&1), 2), (vec![], None));
+    }
+
+    #[test]
+    fn test_size_counter() {
+        let mut bpt = BPlusTree::<u64, u64>::new();
+        assert!(bpt.is_empty());
+
+        for k in 0..50 {
+            bpt.insert(&(k % 30), &k);
+        }
+        assert_eq!(bpt.len(), 30);
+        assert!(bpt.validate());
+
+        for k in 10..20 {
+            bpt.compare_and_swap(&k, Some(&(k + 30)), None).unwrap();
+        }
+        bpt.apply_diff(vec![Diff::Removed(25, 25), Diff::Removed(99, 0), Diff::Added(40, 40)].into_iter());
+        assert_eq!(bpt.len(), 20);
+        assert!(bpt.size_counter_is_consistent());
+
+        bpt.truncate(12);
+        assert_eq!(bpt.len(), 12);
+        assert!(bpt.validate());
+
+        bpt.compact();
+        assert_eq!(bpt.len(), 12);
+        assert!(bpt.validate());
+
+        let mut bpt = from_leaves(vec![vec![1, 2], vec![3, 4], vec![5]]);
+        assert!(bpt.validate());
+        bpt.size += 1;
+        assert!(!bpt.size_counter_is_consistent());
+        assert!(!bpt.validate());
+    }
+
+    #[test]
+    fn test_range_walk_across_levels() {
+        use std::ops::Bound::{Excluded, Included, Unbounded};
+        use std::ops::RangeBounds;
+
+        let bpt = BPlusTree::<u64, u64>::from_iter_last_wins((0..105).map(|k| (k * 2, k)));
+        assert_eq!(BPlusTree::height(bpt.root.as_ref().unwrap()), 4);
+
+        let all: Vec<u64> = bpt.entries().map(|(k, _)| *k).collect();
+        assert_eq!(all, (0..105).map(|k| k * 2).collect::<Vec<_>>());
+
+        for lo in 0..212 {
+            for &(start, end) in &[(Included(lo), Excluded(lo + 37)), (Excluded(lo), Included(lo + 9)), (Included(lo), Unbounded)] {
+                let walked: Vec<u64> = bpt.range_entries((start, end)).map(|(k, _)| *k).collect();
+                let expected: Vec<u64> = all.iter().cloned().filter(|k| (start, end).contains(k)).collect();
+                assert_eq!(walked, expected);
+            }
+
+            let (page, _) = bpt.page_desc(Some(&lo), 7);
+            let expected: Vec<u64> = all.iter().rev().cloned().filter(|&k| k < lo).take(7).collect();
+            assert_eq!(page.iter().map(|&(k, _)| *k).collect::<Vec<_>>(), expected);
+        }
+
+        /* empty leaves are stepped over in both directions */
+        let mut bpt = from_leaves(vec![vec![1, 2], vec![3], vec![4], vec![7, 8]]);
+        bpt.remove_entry(&3);
+        bpt.remove_entry(&4);
+        assert_eq!(bpt.range_entries((Included(2), Unbounded)).map(|(k, _)| *k).collect::<Vec<_>>(), vec![2, 7, 8]);
+        assert_eq!(bpt.page_desc(Some(&7), 5).0, vec![(&2, &20), (&1, &10)]);
+    }
+
+    #[test]
+    fn test_iter_from() {
+        let bpt = from_leaves(vec![vec![1, 3, 5], vec![7, 9], vec![11, 13, 15]]);
+
+        let first: Vec<_> = bpt.entries().take(4).collect();
+        let last = *first.last().unwrap().0;
+        let rest: Vec<_> = bpt.iter_from(&last).collect();
+
+        assert_eq!(first.len() + rest.len(), bpt.len());
+        assert_eq!(first.into_iter().chain(rest).collect::<Vec<_>>(), bpt.entries().collect::<Vec<_>>());
+
+        /* the cursor doesn't have to be a stored key */
+        assert_eq!(bpt.iter_from(&10).map(|(k, _)| *k).collect::<Vec<_>>(), vec![11, 13, 15]);
+        assert_eq!(bpt.iter_from(&15).count(), 0);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_drain_range() {
+        let mut bpt = from_leaves(vec![vec![1, 2, 3], vec![4, 5, 6], vec![7, 8, 9]]);
+
+        let drained: Vec<_> = bpt.drain_range(3..7).collect();
+        assert_eq!(drained, vec![(3, 30), (4, 40), (5, 50), (6, 60)]);
+        assert_eq!(bpt.entries().map(|(k, _)| *k).collect::<Vec<_>>(), vec![1, 2, 7, 8, 9]);
+        assert!(bpt.validate());
+
+        /* only take one, the rest of the range still goes */
+        {
+            let mut drain = bpt.drain_range(8..);
+            assert_eq!(drain.len(), 2);
+            assert_eq!(drain.next(), Some((8, 80)));
+        }
+        assert_eq!(bpt.entries().map(|(k, _)| *k).collect::<Vec<_>>(), vec![1, 2, 7]);
+        assert_eq!(bpt.len(), 3);
+        assert!(bpt.validate());
+
+        assert_eq!(bpt.drain_range(3..7).count(), 0);
+
+        /* a range inside a single leaf */
+        assert_eq!(bpt.drain_range(2..=2).collect::<Vec<_>>(), vec![(2, 20)]);
+        assert_eq!(bpt.entries().map(|(k, _)| *k).collect::<Vec<_>>(), vec![1, 7]);
+        assert!(bpt.validate());
+
+        /* a range spanning whole subtrees leaves no empty leaves behind */
+        let mut bpt = BPlusTree::<u64, u64>::from_sorted_vec((0..200).map(|k| (k, k)).collect());
+        assert_eq!(bpt.drain_range(10..190).len(), 180);
+        assert!(bpt.leaves().iter().all(|leaf| !leaf.keys.is_empty()));
+        assert!(bpt.leaves().len() <= 6);
+        assert!(bpt.validate());
+
+        let report = bpt.balance_report();
+        assert_eq!(report.min_leaf_depth, report.max_leaf_depth);
+
+        for k in 0..200 {
+            bpt.insert(&k, &0);
+        }
+        assert_eq!(bpt.len(), 200);
+        assert!(bpt.validate());
+
+        /* draining everything leaves no root at all */
+        assert_eq!(bpt.drain_range(..).len(), 200);
+        assert!(bpt.root.is_none());
+        assert!(bpt.validate());
+    }
+}